@@ -0,0 +1,117 @@
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::VirtAddr;
+
+/* the few ACPI tables needed to find the CPUs
+
+   RSDP (Root System Description Pointer): found by scanning the BIOS memory, points to the RSDT
+   (32-bit entries) or, from ACPI 2.0, to the XSDT (64-bit entries)
+   each entry of the root table is the physical address of a table starting with a common header,
+   the MADT (Multiple APIC Description Table, signature "APIC") lists the local APIC of each CPU
+
+   the tables are read through the physical memory mapping, and may not be aligned
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NoRsdp,
+    NoMadt,
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    // physical address of the local APIC registers, the same for every CPU
+    pub local_apic_address: u64,
+    // local APIC IDs of the enabled CPUs, the bootstrap CPU included
+    pub apic_ids: Vec<u32>,
+}
+
+const HEADER_SIZE: u64 = 36;
+
+pub fn find_madt(physical_memory_offset: VirtAddr) -> Result<Madt, AcpiError> {
+    let tables = Tables { physical_memory_offset };
+    let rsdp = tables.find_rsdp().ok_or(AcpiError::NoRsdp)?;
+    let revision: u8 = tables.read(rsdp + 15);
+    let (root, entry_size) = if revision >= 2 {
+        (tables.read::<u64>(rsdp + 24), 8)
+    } else {
+        (u64::from(tables.read::<u32>(rsdp + 16)), 4)
+    };
+
+    let length = u64::from(tables.read::<u32>(root + 4));
+    let entries = (length.saturating_sub(HEADER_SIZE)) / entry_size;
+    (0..entries)
+        .map(|index| {
+            let entry = root + HEADER_SIZE + index * entry_size;
+            if entry_size == 8 { tables.read::<u64>(entry) } else { u64::from(tables.read::<u32>(entry)) }
+        })
+        .find(|&table| tables.signature(table) == *b"APIC")
+        .map(|madt| tables.parse_madt(madt))
+        .ok_or(AcpiError::NoMadt)
+}
+
+struct Tables {
+    physical_memory_offset: VirtAddr,
+}
+
+impl Tables {
+    fn read<T: Copy>(&self, phys: u64) -> T {
+        let virt = self.physical_memory_offset + phys;
+        unsafe { ptr::read_unaligned(virt.as_ptr()) }
+    }
+
+    fn signature(&self, phys: u64) -> [u8; 4] {
+        self.read(phys)
+    }
+
+    fn checksum_ok(&self, phys: u64, length: u64) -> bool {
+        (0..length).fold(0u8, |sum, offset| sum.wrapping_add(self.read(phys + offset))) == 0
+    }
+
+    // in the first KiB of the EBDA (Extended BIOS Data Area) or in the BIOS area below 1 MiB,
+    // on a 16 bytes boundary
+    fn find_rsdp(&self) -> Option<u64> {
+        let ebda = u64::from(self.read::<u16>(0x40e)) << 4;
+        let ebda_area = (ebda..ebda + 1024).step_by(16);
+        let bios_area = (0xe_0000..0x10_0000).step_by(16);
+        ebda_area
+            .chain(bios_area)
+            .filter(|&phys| phys != 0)
+            .find(|&phys| self.read::<[u8; 8]>(phys) == *b"RSD PTR " && self.checksum_ok(phys, 20))
+    }
+
+    /* entries after the header, the local APIC address and the flags:
+       - type (1 byte), length (1 byte), then the content
+       - type 0: processor local APIC, ACPI ID (1 byte), APIC ID (1 byte), flags (4 bytes)
+       - type 5: 64-bit local APIC address override
+    */
+    fn parse_madt(&self, madt: u64) -> Madt {
+        let length = u64::from(self.read::<u32>(madt + 4));
+        let mut local_apic_address = u64::from(self.read::<u32>(madt + HEADER_SIZE));
+        let mut apic_ids = Vec::new();
+
+        let mut entry = madt + HEADER_SIZE + 8;
+        while entry + 2 <= madt + length {
+            let entry_type: u8 = self.read(entry);
+            let entry_length: u8 = self.read(entry + 1);
+            match entry_type {
+                0 => {
+                    let apic_id: u8 = self.read(entry + 3);
+                    let flags: u32 = self.read(entry + 4);
+                    // bit 0: enabled
+                    if flags & 1 != 0 {
+                        apic_ids.push(u32::from(apic_id));
+                    }
+                }
+                5 => local_apic_address = self.read(entry + 4),
+                _ => {}
+            }
+            if entry_length < 2 {
+                break;
+            }
+            entry += u64::from(entry_length);
+        }
+
+        Madt { local_apic_address, apic_ids }
+    }
+}
@@ -1,18 +1,27 @@
-use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+use alloc::alloc::{AllocError, GlobalAlloc, Layout};
+use alloc::{boxed::Box, collections::TryReserveError, vec::Vec};
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, null_mut};
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB
     },
     VirtAddr,
 };
 
-// pub mod bump;
-// use bump::BumpAllocator;
-// pub mod linked_list;
-// use linked_list::LinkedListAllocator;
+pub mod bump;
+pub mod linked_list;
 pub mod fixed_size_block;
-use fixed_size_block::FixedSizeBlockAllocator;
+pub mod buddy;
+pub mod stats;
+pub mod object_cache;
+pub mod debug;
+pub mod tracker;
+pub mod arena;
+pub mod pressure;
+use stats::{AllocatorStats, HeapStats};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB
@@ -55,10 +64,68 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
     
+// the global allocator is selected with one of the `bump`, `linked_list`, `fixed_size_block` or `buddy` features
+// (see Cargo.toml), e.g. `cargo xrun --no-default-features --features linked_list`
+#[cfg(not(any(feature = "bump", feature = "linked_list", feature = "fixed_size_block", feature = "buddy")))]
+compile_error!("no global allocator selected, enable one of the `bump`, `linked_list`, `fixed_size_block` or `buddy` features");
+
+#[cfg(any(
+    all(feature = "bump", feature = "linked_list"),
+    all(feature = "bump", feature = "fixed_size_block"),
+    all(feature = "bump", feature = "buddy"),
+    all(feature = "linked_list", feature = "fixed_size_block"),
+    all(feature = "linked_list", feature = "buddy"),
+    all(feature = "fixed_size_block", feature = "buddy"),
+))]
+compile_error!("only one of the `bump`, `linked_list`, `fixed_size_block` or `buddy` features can be enabled");
+
+#[cfg(feature = "bump")]
+type GlobalAllocator = bump::BumpAllocator;
+#[cfg(feature = "linked_list")]
+type GlobalAllocator = linked_list::LinkedListAllocator;
+#[cfg(feature = "fixed_size_block")]
+type GlobalAllocator = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "buddy")]
+type GlobalAllocator = buddy::BuddyAllocator;
+
+static ALLOCATOR: Locked<GlobalAllocator> = Locked::new(GlobalAllocator::new());
+
+// failed allocations are retried after running the memory pressure callbacks
+type HeapAllocator = pressure::PressureAllocator<Locked<GlobalAllocator>>;
+
+// with the `heap_debug` / `heap_track` features every allocation goes through `DebugAllocator` / `TrackingAllocator` first
+#[cfg_attr(not(any(feature = "heap_debug", feature = "heap_track")), global_allocator)]
+static PRESSURE_ALLOCATOR: HeapAllocator = pressure::PressureAllocator::new(&ALLOCATOR);
+
+#[cfg(feature = "heap_debug")]
+#[cfg_attr(not(feature = "heap_track"), global_allocator)]
+static DEBUG_ALLOCATOR: debug::DebugAllocator<HeapAllocator> = debug::DebugAllocator::new(&PRESSURE_ALLOCATOR);
+
+#[cfg(all(feature = "heap_track", feature = "heap_debug"))]
 #[global_allocator]
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
-// static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static TRACKING_ALLOCATOR: tracker::TrackingAllocator<debug::DebugAllocator<HeapAllocator>> =
+    tracker::TrackingAllocator::new(&DEBUG_ALLOCATOR);
+
+#[cfg(all(feature = "heap_track", not(feature = "heap_debug")))]
+#[global_allocator]
+static TRACKING_ALLOCATOR: tracker::TrackingAllocator<HeapAllocator> =
+    tracker::TrackingAllocator::new(&PRESSURE_ALLOCATOR);
+
+// live allocations grouped by call site, `snapshot().dump()` prints them over serial
+#[cfg(feature = "heap_track")]
+pub fn snapshot() -> tracker::Snapshot {
+    TRACKING_ALLOCATOR.snapshot()
+}
+
+// name of the selected global allocator
+#[cfg(feature = "bump")]
+pub const GLOBAL_ALLOCATOR: &str = "bump";
+#[cfg(feature = "linked_list")]
+pub const GLOBAL_ALLOCATOR: &str = "linked_list";
+#[cfg(feature = "fixed_size_block")]
+pub const GLOBAL_ALLOCATOR: &str = "fixed_size_block";
+#[cfg(feature = "buddy")]
+pub const GLOBAL_ALLOCATOR: &str = "buddy";
 
 // wrapper into spin::Mutex
 pub struct Locked<A> {
@@ -72,13 +139,87 @@ impl<A> Locked<A> {
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
+    // interrupts are disabled while the lock is held: neither an interrupt handler nor the thread
+    // scheduler can run, so a preempted thread never holds the heap
+    pub fn lock(&self) -> LockedGuard<A> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        LockedGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_enabled,
+        }
+    }
+}
+
+pub struct LockedGuard<'a, A> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, A>>,
+    interrupts_enabled: bool,
+}
+
+impl<A> Deref for LockedGuard<'_, A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.guard
     }
 }
 
+impl<A> DerefMut for LockedGuard<'_, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        &mut self.guard
+    }
+}
+
+impl<A> Drop for LockedGuard<'_, A> {
+    fn drop(&mut self) {
+        // unlock before an interrupt can come
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+impl<A: AllocatorStats> Locked<A> {
+    pub fn stats(&self) -> HeapStats {
+        self.lock().stats()
+    }
+}
+
+// stats of the global allocator, can be printed as a report with `println!("{}", stats())`
+pub fn stats() -> HeapStats {
+    let stats = ALLOCATOR.stats();
+    // with the sizes requested by the users, not those of the red zones and headers
+    #[cfg(feature = "heap_debug")]
+    let stats = DEBUG_ALLOCATOR.user_stats(stats);
+    stats
+}
+
+// fallible allocations for the code paths that must not end in `alloc_error_handler`
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    Box::try_new(value)
+}
+
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, TryReserveError> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(capacity)?;
+    Ok(vec)
+}
+
+// realloc without support from the allocator: allocate a new block, copy and free the old one
+// (same as the default implementation of `GlobalAlloc::realloc`)
+unsafe fn realloc_by_copy<A: GlobalAlloc>(allocator: &A, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+    let new_ptr = allocator.alloc(new_layout);
+    if !new_ptr.is_null() {
+        ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+        allocator.dealloc(ptr, layout);
+    }
+    new_ptr
+}
+
 // align the given address `addr` upwards to alignment `align`
-/*fn align_up(addr: usize, align: usize) -> usize {
+fn align_up(addr: usize, align: usize) -> usize {
     /* slower method but easier to understand
     let remainder = addr % align;
     if remainder == 0 {
@@ -96,4 +237,4 @@ impl<A> Locked<A> {
     - increase by (align - 1) to round non aligned addresses tot he next alignment
     */
     (addr + align - 1) & !(align - 1)
-}*/
\ No newline at end of file
+}
@@ -0,0 +1,89 @@
+use super::{Locked, bump::BumpAllocator};
+use super::fixed_size_block::SLAB_SIZE;
+use super::stats::HeapStats;
+use alloc::alloc::{alloc, dealloc, AllocError, Allocator, GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+/* scoped arena: a BumpAllocator working on pages taken from the kernel heap
+
+   allocations are cheap (bump a pointer) and freeing them does nothing until every allocation
+   of the arena is freed, the pages go back to the kernel heap when the arena is dropped
+
+       let arena = Arena::new(16 * 1024)?;
+       let mut vec = Vec::new_in(&arena);
+*/
+
+pub struct Arena {
+    bump: Locked<BumpAllocator>,
+    memory: NonNull<u8>,
+    layout: Layout,
+}
+
+// `memory` is owned by the arena and only handed out through `bump`, which has its own lock
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
+
+impl Arena {
+    // `size` is rounded up to a multiple of the page size
+    pub fn new(size: usize) -> Result<Self, AllocError> {
+        let layout = Layout::from_size_align(size.max(1), SLAB_SIZE)
+            .map_err(|_| AllocError)?
+            .pad_to_align();
+        let memory = NonNull::new(unsafe { alloc(layout) }).ok_or(AllocError)?;
+
+        let bump = Locked::new(BumpAllocator::new());
+        unsafe { bump.lock().init(memory.as_ptr() as usize, layout.size()) };
+        Ok(Arena { bump, memory, layout })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.layout.size()
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.bump.stats()
+    }
+
+    // the last allocation is resized in place by `BumpAllocator::realloc`, so a growing Vec
+    // doesn't use up the arena
+    unsafe fn resize(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = if new_layout.align() == old_layout.align() {
+            self.bump.realloc(ptr.as_ptr(), old_layout, new_layout.size())
+        } else {
+            let new_ptr = self.bump.alloc(new_layout);
+            if !new_ptr.is_null() {
+                ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr, old_layout.size().min(new_layout.size()));
+                self.bump.dealloc(ptr.as_ptr(), old_layout);
+            }
+            new_ptr
+        };
+        let new_ptr = NonNull::new(new_ptr).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(new_ptr, new_layout.size()))
+    }
+}
+
+unsafe impl Allocator for Arena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = NonNull::new(unsafe { self.bump.alloc(layout) }).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.bump.dealloc(ptr.as_ptr(), layout)
+    }
+
+    unsafe fn grow(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        // every allocation borrows the arena, so none of them is still alive
+        unsafe { dealloc(self.memory.as_ptr(), self.layout) };
+    }
+}
@@ -0,0 +1,247 @@
+use super::{align_up, realloc_by_copy, Locked, HEAP_SIZE};
+use super::stats::{AllocatorStats, Counters, HeapStats};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+
+/* power-of-two buddy allocator
+    - a block of order k has a size of MIN_BLOCK_SIZE * 2^k and is aligned on its size,
+      so its buddy (the other half of the parent block) is at `addr ^ size`
+    - alloc splits a larger block until it has the right order, dealloc merges the block
+      with its buddy as long as the buddy is free
+    - the state of each block (order + free flag) is kept in `block_states`, on the entry
+      of the first MIN_BLOCK_SIZE granule of the block
+*/
+
+// large enough to hold a `FreeBlock`
+const MIN_BLOCK_SIZE: usize = 16;
+const MAX_ORDER: usize = 20; // 16 MiB blocks
+const ORDERS: usize = MAX_ORDER + 1;
+const GRANULES: usize = HEAP_SIZE / MIN_BLOCK_SIZE;
+
+const FREE_FLAG: u8 = 0x80;
+
+// node of the (doubly linked) free lists, stored in the free block
+struct FreeBlock {
+    prev: *mut FreeBlock,
+    next: *mut FreeBlock,
+}
+
+pub struct BuddyAllocator {
+    free_lists: [*mut FreeBlock; ORDERS],
+    block_states: [u8; GRANULES],
+    heap_start: usize,
+    heap_end: usize,
+    counters: Counters,
+}
+
+// the free blocks are only accessed while holding the lock of `Locked`
+unsafe impl Send for BuddyAllocator {}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        BuddyAllocator {
+            free_lists: [ptr::null_mut(); ORDERS],
+            block_states: [0; GRANULES],
+            heap_start: 0,
+            heap_end: 0,
+            counters: Counters::new(),
+        }
+    }
+
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        assert!(mem::size_of::<FreeBlock>() <= MIN_BLOCK_SIZE);
+        // every granule must have an entry in `block_states`
+        assert!(heap_size <= GRANULES * MIN_BLOCK_SIZE);
+
+        self.heap_start = align_up(heap_start, MIN_BLOCK_SIZE);
+        self.heap_end = (heap_start + heap_size) & !(MIN_BLOCK_SIZE - 1);
+
+        // split the heap into the largest aligned blocks that fit
+        let mut addr = self.heap_start;
+        while addr < self.heap_end {
+            let order = (0..ORDERS).rev()
+                .find(|&order| addr % Self::block_size(order) == 0 && addr + Self::block_size(order) <= self.heap_end)
+                .unwrap();
+            self.push_free(addr, order);
+            addr += Self::block_size(order);
+        }
+    }
+
+    fn block_size(order: usize) -> usize {
+        MIN_BLOCK_SIZE << order
+    }
+
+    // smallest order whose blocks can hold `layout`, the block alignment is its size
+    fn order(layout: &Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align()).max(MIN_BLOCK_SIZE).next_power_of_two();
+        let order = (size / MIN_BLOCK_SIZE).trailing_zeros() as usize;
+        if order < ORDERS { Some(order) } else { None }
+    }
+
+    fn state_index(&self, addr: usize) -> usize {
+        (addr - self.heap_start) / MIN_BLOCK_SIZE
+    }
+
+    fn is_free(&self, addr: usize, order: usize) -> bool {
+        self.block_states[self.state_index(addr)] == FREE_FLAG | order as u8
+    }
+
+    unsafe fn push_free(&mut self, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        let head = self.free_lists[order];
+        block.write(FreeBlock { prev: ptr::null_mut(), next: head });
+        if !head.is_null() {
+            (*head).prev = block;
+        }
+        self.free_lists[order] = block;
+
+        let index = self.state_index(addr);
+        self.block_states[index] = FREE_FLAG | order as u8;
+    }
+
+    // remove the block at `addr` from the free list of `order`
+    unsafe fn remove_free(&mut self, addr: usize, order: usize) {
+        let block = &mut *(addr as *mut FreeBlock);
+        if block.prev.is_null() {
+            self.free_lists[order] = block.next;
+        } else {
+            (*block.prev).next = block.next;
+        }
+        if !block.next.is_null() {
+            (*block.next).prev = block.prev;
+        }
+
+        let index = self.state_index(addr);
+        self.block_states[index] = order as u8;
+    }
+
+    fn alloc_block(&mut self, layout: Layout) -> *mut u8 {
+        let order = match Self::order(&layout) {
+            Some(order) => order,
+            None => return ptr::null_mut(),
+        };
+
+        // smallest free block large enough
+        let mut current_order = match (order..ORDERS).find(|&o| !self.free_lists[o].is_null()) {
+            Some(current_order) => current_order,
+            None => return ptr::null_mut(),
+        };
+
+        let addr = self.free_lists[current_order] as usize;
+        unsafe {
+            self.remove_free(addr, current_order);
+            // give back the upper halves until the block has the right order
+            while current_order > order {
+                current_order -= 1;
+                self.push_free(addr + Self::block_size(current_order), current_order);
+            }
+        }
+
+        let index = self.state_index(addr);
+        self.block_states[index] = order as u8;
+        self.counters.record_alloc(layout.size());
+        addr as *mut u8
+    }
+
+    unsafe fn dealloc_block(&mut self, ptr: *mut u8, layout: Layout) {
+        let mut order = Self::order(&layout).unwrap();
+        let mut addr = ptr as usize;
+
+        // merge with the buddy as long as it is free
+        while order < MAX_ORDER {
+            let buddy = addr ^ Self::block_size(order);
+            let buddy_in_heap = buddy >= self.heap_start && buddy + Self::block_size(order) <= self.heap_end;
+            if !buddy_in_heap || !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove_free(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+
+        self.push_free(addr, order);
+        self.counters.record_dealloc(layout.size());
+    }
+
+    // resize the block without moving it: merge the following buddies when growing, give back
+    // the upper halves when shrinking, return false if it's not possible
+    unsafe fn realloc_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let order = Self::order(&layout).unwrap();
+        let new_order = match Self::order(&Layout::from_size_align_unchecked(new_size, layout.align())) {
+            Some(new_order) => new_order,
+            None => return false,
+        };
+        let addr = ptr as usize;
+
+        if new_order > order {
+            // the block must be the lower half at each level and every upper half must be free
+            let mergeable = (order..new_order).all(|o| {
+                let buddy = addr + Self::block_size(o);
+                addr & Self::block_size(o) == 0
+                    && buddy + Self::block_size(o) <= self.heap_end
+                    && self.is_free(buddy, o)
+            });
+            if !mergeable {
+                return false;
+            }
+            for o in order..new_order {
+                self.remove_free(addr + Self::block_size(o), o);
+            }
+        } else {
+            // the buddies of the upper halves are part of the block, so they can't be merged
+            for o in (new_order..order).rev() {
+                self.push_free(addr + Self::block_size(o), o);
+            }
+        }
+
+        let index = self.state_index(addr);
+        self.block_states[index] = new_order as u8;
+        self.counters.record_realloc(layout.size(), new_size);
+        true
+    }
+}
+
+impl AllocatorStats for BuddyAllocator {
+    fn stats(&self) -> HeapStats {
+        let mut free_bytes = 0;
+        let mut largest_free_block = 0;
+        for (order, &head) in self.free_lists.iter().enumerate() {
+            let mut block = head;
+            while !block.is_null() {
+                free_bytes += Self::block_size(order);
+                largest_free_block = Self::block_size(order);
+                block = unsafe { (*block).next };
+            }
+        }
+
+        HeapStats {
+            heap_size: self.heap_end - self.heap_start,
+            bytes_allocated: self.counters.bytes_allocated,
+            peak_bytes_allocated: self.counters.peak_bytes_allocated,
+            allocations: self.counters.allocations,
+            deallocations: self.counters.deallocations,
+            free_list_lengths: None,
+            free_bytes,
+            largest_free_block,
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().alloc_block(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().dealloc_block(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let in_place = self.lock().realloc_in_place(ptr, layout, new_size);
+        if in_place {
+            ptr
+        } else {
+            realloc_by_copy(self, ptr, layout, new_size)
+        }
+    }
+}
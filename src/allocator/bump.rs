@@ -1,4 +1,5 @@
-use super::{align_up, Locked};
+use super::{align_up, realloc_by_copy, Locked};
+use super::stats::{AllocatorStats, Counters, HeapStats};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -7,6 +8,7 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    counters: Counters,
 }
 
 impl BumpAllocator {
@@ -16,6 +18,7 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            counters: Counters::new(),
         }
     }
 
@@ -26,6 +29,23 @@ impl BumpAllocator {
     }
 }
 
+impl AllocatorStats for BumpAllocator {
+    fn stats(&self) -> HeapStats {
+        // freed memory is only reused once every allocation is freed
+        let free_bytes = self.heap_end - self.next;
+        HeapStats {
+            heap_size: self.heap_end - self.heap_start,
+            bytes_allocated: self.counters.bytes_allocated,
+            peak_bytes_allocated: self.counters.peak_bytes_allocated,
+            allocations: self.counters.allocations,
+            deallocations: self.counters.deallocations,
+            free_list_lengths: None,
+            free_bytes,
+            largest_free_block: free_bytes,
+        }
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // get mutable ref
@@ -45,18 +65,34 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            bump.counters.record_alloc(layout.size());
             alloc_start as *mut u8
         }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock();
 
         bump.allocations -= 1;
+        bump.counters.record_dealloc(layout.size());
 
         // reset allocator
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
         }
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        {
+            let mut bump = self.lock();
+            // the last allocation can be resized by moving `next`
+            let new_end = ptr as usize + new_size;
+            if ptr as usize + layout.size() == bump.next && new_end <= bump.heap_end {
+                bump.next = new_end;
+                bump.counters.record_realloc(layout.size(), new_size);
+                return ptr;
+            }
+        }
+        realloc_by_copy(self, ptr, layout, new_size)
+    }
 }
\ No newline at end of file
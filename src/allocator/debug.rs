@@ -0,0 +1,183 @@
+use super::stats::{Counters, HeapStats};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr, slice};
+
+/* wrapper around the global allocator used with the `heap_debug` feature
+
+   inner allocation:
+   | padding | Header | front red zone | user data (layout.size()) | back red zone |
+                                       ^ pointer returned by alloc
+
+   - red zones are filled with RED_ZONE_BYTE and checked on dealloc
+   - freed user data is filled with POISON_BYTE and stays in a quarantine for a while, so that
+     a write after free is detected when it leaves the quarantine, and a double free is detected
+     by the header state
+   - the sizes requested by the users are counted here, the inner allocator only sees the larger
+     inner allocations and the blocks in quarantine
+*/
+
+const RED_ZONE_SIZE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xfd;
+const UNINIT_BYTE: u8 = 0xcd;
+const POISON_BYTE: u8 = 0xdd;
+pub const QUARANTINE_SIZE: usize = 32;
+
+const ALLOCATED_MAGIC: usize = 0xa110_ca7e_d0d0_a110;
+const FREED_MAGIC: usize = 0xf4ee_d0d0_f4ee_d0d0;
+
+struct Header {
+    magic: usize,
+    size: usize,
+    align: usize,
+}
+
+// freed block waiting to be given back to the inner allocator
+#[derive(Clone, Copy)]
+struct QuarantinedBlock {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+struct Quarantine {
+    blocks: [Option<QuarantinedBlock>; QUARANTINE_SIZE],
+    // index of the oldest block
+    next: usize,
+}
+
+// blocks are only accessed while holding the lock
+unsafe impl Send for Quarantine {}
+
+pub struct DebugAllocator<A: 'static> {
+    inner: &'static A,
+    quarantine: super::Locked<Quarantine>,
+    // by user size, a block is freed when it goes to the quarantine
+    counters: super::Locked<Counters>,
+}
+
+impl<A: GlobalAlloc> DebugAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        DebugAllocator {
+            inner,
+            quarantine: super::Locked::new(Quarantine {
+                blocks: [None; QUARANTINE_SIZE],
+                next: 0,
+            }),
+            counters: super::Locked::new(Counters::new()),
+        }
+    }
+
+    // `heap` (the stats of the inner allocator) with the allocations seen by the users
+    pub fn user_stats(&self, heap: HeapStats) -> HeapStats {
+        let counters = *self.counters.lock();
+        HeapStats {
+            bytes_allocated: counters.bytes_allocated,
+            peak_bytes_allocated: counters.peak_bytes_allocated,
+            allocations: counters.allocations,
+            deallocations: counters.deallocations,
+            ..heap
+        }
+    }
+
+    // offset of the user data in the inner allocation
+    fn front_size(layout: Layout) -> usize {
+        let front = mem::size_of::<Header>() + RED_ZONE_SIZE;
+        (front + layout.align() - 1) & !(layout.align() - 1)
+    }
+
+    fn inner_layout(layout: Layout) -> Layout {
+        let size = Self::front_size(layout) + layout.size() + RED_ZONE_SIZE;
+        let align = layout.align().max(mem::align_of::<Header>());
+        Layout::from_size_align(size, align).expect("heap_debug: layout too large")
+    }
+
+    unsafe fn header(ptr: *mut u8) -> *mut Header {
+        ptr.sub(RED_ZONE_SIZE + mem::size_of::<Header>()) as *mut Header
+    }
+
+    // panic if a byte of the region isn't `expected`
+    unsafe fn check_filled(start: *const u8, len: usize, expected: u8, what: &str, ptr: *mut u8, layout: Layout) {
+        let bytes = slice::from_raw_parts(start, len);
+        if let Some(offset) = bytes.iter().position(|&b| b != expected) {
+            panic!(
+                "heap_debug: {} of the block at {:p} ({:?}) overwritten at byte {} (found {:#x}, expected {:#x})",
+                what, ptr, layout, offset, bytes[offset], expected
+            );
+        }
+    }
+
+    // give every quarantined block back to the inner allocator
+    unsafe fn flush_quarantine(&self) {
+        let mut quarantine = self.quarantine.lock();
+        for slot in quarantine.blocks.iter_mut() {
+            if let Some(block) = slot.take() {
+                self.release(block);
+            }
+        }
+    }
+
+    unsafe fn release(&self, block: QuarantinedBlock) {
+        Self::check_filled(block.ptr, block.layout.size(), POISON_BYTE, "freed memory", block.ptr, block.layout);
+        let base = block.ptr.sub(Self::front_size(block.layout));
+        self.inner.dealloc(base, Self::inner_layout(block.layout));
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let inner_layout = Self::inner_layout(layout);
+        let mut base = self.inner.alloc(inner_layout);
+        if base.is_null() {
+            // the quarantine might be holding the memory we need
+            self.flush_quarantine();
+            base = self.inner.alloc(inner_layout);
+            if base.is_null() {
+                return ptr::null_mut();
+            }
+        }
+
+        let ptr = base.add(Self::front_size(layout));
+        Self::header(ptr).write(Header {
+            magic: ALLOCATED_MAGIC,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        ptr::write_bytes(ptr.sub(RED_ZONE_SIZE), RED_ZONE_BYTE, RED_ZONE_SIZE);
+        ptr::write_bytes(ptr, UNINIT_BYTE, layout.size());
+        ptr::write_bytes(ptr.add(layout.size()), RED_ZONE_BYTE, RED_ZONE_SIZE);
+        self.counters.lock().record_alloc(layout.size());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = &mut *Self::header(ptr);
+        match header.magic {
+            ALLOCATED_MAGIC => {}
+            FREED_MAGIC => panic!("heap_debug: double free of the block at {:p} ({:?})", ptr, layout),
+            _ => panic!("heap_debug: dealloc of {:p} ({:?}) which was not allocated or whose header was overwritten", ptr, layout),
+        }
+        if header.size != layout.size() || header.align != layout.align() {
+            panic!(
+                "heap_debug: dealloc of the block at {:p} with {:?} but it was allocated with size {} and align {}",
+                ptr, layout, header.size, header.align
+            );
+        }
+
+        Self::check_filled(ptr.sub(RED_ZONE_SIZE), RED_ZONE_SIZE, RED_ZONE_BYTE, "front red zone", ptr, layout);
+        Self::check_filled(ptr.add(layout.size()), RED_ZONE_SIZE, RED_ZONE_BYTE, "back red zone", ptr, layout);
+
+        header.magic = FREED_MAGIC;
+        ptr::write_bytes(ptr, POISON_BYTE, layout.size());
+        self.counters.lock().record_dealloc(layout.size());
+
+        // replace the oldest block of the quarantine
+        let evicted = {
+            let mut quarantine = self.quarantine.lock();
+            let index = quarantine.next;
+            quarantine.next = (index + 1) % QUARANTINE_SIZE;
+            quarantine.blocks[index].replace(QuarantinedBlock { ptr, layout })
+        };
+        if let Some(block) = evicted {
+            self.release(block);
+        }
+    }
+}
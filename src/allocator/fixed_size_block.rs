@@ -1,4 +1,6 @@
-use super::Locked;
+use super::{align_up, realloc_by_copy, Locked, HEAP_SIZE};
+use super::linked_list::LinkedListAllocator;
+use super::stats::{AllocatorStats, Counters, HeapStats};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{ptr, ptr::NonNull, mem};
 
@@ -7,77 +9,281 @@ struct ListNode {
 }
 
 // 2^n because they are also used as the block alignment which must be 2^n
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/* the blocks of each size come from page-sized slabs taken from the fallback allocator
+
+   - slabs are aligned on their size, so the slab of a block is found by clearing the low bits of
+     its address, and its bookkeeping is indexed by its offset in the heap
+   - each slab has its own free list, and the slabs of a size class with free blocks are linked
+     together: alloc and dealloc never walk a list
+   - a slab is carved lazily, the blocks never handed out are not in its free list
+   - each size class keeps one slab whose blocks are all free as a spare, so that allocating and
+     freeing a single block doesn't take and give back a slab every time, the other empty slabs
+     go back to the fallback allocator at once
+   - the spare slabs are given back too when the fallback allocator runs out of memory
+*/
+
+pub const SLAB_SIZE: usize = 4096;
+const MAX_SLABS: usize = HEAP_SIZE / SLAB_SIZE;
+// end of a list of slabs
+const NO_SLAB: usize = usize::MAX;
+
+struct Slab {
+    // blocks freed since they were handed out
+    free_list: Option<&'static mut ListNode>,
+    // blocks handed out at least once, the next ones come after them
+    carved: usize,
+    // in the free list or not carved yet
+    free_blocks: usize,
+    // neighbours in the list of the slabs with free blocks of the size class
+    prev: usize,
+    next: usize,
+}
+
+impl Slab {
+    const EMPTY: Slab = Slab {
+        free_list: None,
+        carved: 0,
+        free_blocks: 0,
+        prev: NO_SLAB,
+        next: NO_SLAB,
+    };
+}
 
 pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
-    fallback_allocator: linked_list_allocator::Heap
+    // first slab with free blocks of each size class
+    partial_slabs: [usize; BLOCK_SIZES.len()],
+    // number of free blocks in the slabs of each size class
+    free_blocks: [usize; BLOCK_SIZES.len()],
+    // slab of each size class whose blocks are all free, kept in `partial_slabs`
+    spare_slabs: [usize; BLOCK_SIZES.len()],
+    // indexed by `slab_index()`, only meaningful for the slabs in use
+    slabs: [Slab; MAX_SLABS],
+    // address of the first possible slab in the heap
+    slab_base: usize,
+    // our own LinkedListAllocator because `linked_list_allocator::Heap` doesn't expose its free list
+    fallback_allocator: LinkedListAllocator,
+    counters: Counters,
 }
 
 impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
         FixedSizeBlockAllocator {
-            list_heads: [None; BLOCK_SIZES.len()],
-            fallback_allocator: linked_list_allocator::Heap::empty()
+            partial_slabs: [NO_SLAB; BLOCK_SIZES.len()],
+            free_blocks: [0; BLOCK_SIZES.len()],
+            spare_slabs: [NO_SLAB; BLOCK_SIZES.len()],
+            slabs: [Slab::EMPTY; MAX_SLABS],
+            slab_base: 0,
+            fallback_allocator: LinkedListAllocator::new(),
+            counters: Counters::new(),
         }
     }
 
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        // every slab must have an entry in `slabs`
+        assert!(heap_size <= MAX_SLABS * SLAB_SIZE);
+
+        self.slab_base = align_up(heap_start, SLAB_SIZE);
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
+        match self.allocate_from_fallback(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
         }
     }
+
+    // retried once the spare slabs are given back, they may be in the way of a large block
+    fn allocate_from_fallback(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        self.fallback_allocator.allocate_first_fit(layout).or_else(|_| {
+            if unsafe { self.release_spare_slabs() } {
+                self.fallback_allocator.allocate_first_fit(layout)
+            } else {
+                Err(())
+            }
+        })
+    }
+
+    fn slab_layout() -> Layout {
+        Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+    }
+
+    fn slab_start(&self, slab: usize) -> usize {
+        self.slab_base + slab * SLAB_SIZE
+    }
+
+    fn slab_index(&self, addr: usize) -> usize {
+        ((addr & !(SLAB_SIZE - 1)) - self.slab_base) / SLAB_SIZE
+    }
+
+    fn blocks_per_slab(index: usize) -> usize {
+        SLAB_SIZE / BLOCK_SIZES[index]
+    }
+
+    // add the slab at the front of the slabs with free blocks of the list `index`
+    fn link_partial(&mut self, index: usize, slab: usize) {
+        let next = self.partial_slabs[index];
+        self.slabs[slab].prev = NO_SLAB;
+        self.slabs[slab].next = next;
+        if next != NO_SLAB {
+            self.slabs[next].prev = slab;
+        }
+        self.partial_slabs[index] = slab;
+    }
+
+    fn unlink_partial(&mut self, index: usize, slab: usize) {
+        let (prev, next) = (self.slabs[slab].prev, self.slabs[slab].next);
+        if prev == NO_SLAB {
+            self.partial_slabs[index] = next;
+        } else {
+            self.slabs[prev].next = next;
+        }
+        if next != NO_SLAB {
+            self.slabs[next].prev = prev;
+        }
+    }
+
+    // take a new slab from the fallback allocator for the list `index`
+    fn refill(&mut self, index: usize) -> Result<(), ()> {
+        let slab_start = self.allocate_from_fallback(Self::slab_layout())?.as_ptr() as usize;
+        let slab = self.slab_index(slab_start);
+        self.slabs[slab] = Slab {
+            free_blocks: Self::blocks_per_slab(index),
+            ..Slab::EMPTY
+        };
+        self.free_blocks[index] += Self::blocks_per_slab(index);
+        self.link_partial(index, slab);
+        Ok(())
+    }
+
+    // take a block from the first slab with free blocks of the list `index`
+    fn pop_block(&mut self, index: usize) -> Option<*mut u8> {
+        let slab = self.partial_slabs[index];
+        if slab == NO_SLAB {
+            return None;
+        }
+
+        let block = match self.slabs[slab].free_list.take() {
+            Some(node) => {
+                // link the slab to the second node
+                self.slabs[slab].free_list = node.next.take();
+                node as *mut ListNode as *mut u8
+            }
+            None => {
+                // the blocks are carved by increasing address
+                let carved = self.slabs[slab].carved;
+                self.slabs[slab].carved += 1;
+                (self.slab_start(slab) + carved * BLOCK_SIZES[index]) as *mut u8
+            }
+        };
+
+        if self.spare_slabs[index] == slab {
+            self.spare_slabs[index] = NO_SLAB;
+        }
+        self.slabs[slab].free_blocks -= 1;
+        self.free_blocks[index] -= 1;
+        if self.slabs[slab].free_blocks == 0 {
+            self.unlink_partial(index, slab);
+        }
+        Some(block)
+    }
+
+    // give the block at `ptr` back to its slab, and the slab to the fallback allocator if it is all
+    // free and the size class already has a spare
+    unsafe fn push_block(&mut self, index: usize, ptr: *mut u8) {
+        // assert that the new node have the correct size / alignment to store a `ListNode`
+        assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+
+        let slab = self.slab_index(ptr as usize);
+        let new_node = ListNode {
+            next: self.slabs[slab].free_list.take()
+        };
+        let new_node_ptr = ptr as *mut ListNode;
+        new_node_ptr.write(new_node);
+        self.slabs[slab].free_list = Some(&mut *new_node_ptr);
+
+        self.slabs[slab].free_blocks += 1;
+        self.free_blocks[index] += 1;
+        if self.slabs[slab].free_blocks == 1 {
+            // it was full
+            self.link_partial(index, slab);
+        }
+        if self.slabs[slab].free_blocks == Self::blocks_per_slab(index) {
+            if self.spare_slabs[index] == NO_SLAB {
+                self.spare_slabs[index] = slab;
+            } else {
+                self.release_slab(index, slab);
+            }
+        }
+    }
+
+    // give the spare slabs back to the fallback allocator, return false if there was none
+    unsafe fn release_spare_slabs(&mut self) -> bool {
+        let mut released = false;
+        for index in 0..BLOCK_SIZES.len() {
+            let slab = mem::replace(&mut self.spare_slabs[index], NO_SLAB);
+            if slab != NO_SLAB {
+                self.release_slab(index, slab);
+                released = true;
+            }
+        }
+        released
+    }
+
+    // give a fully free slab of the list `index` back to the fallback allocator
+    unsafe fn release_slab(&mut self, index: usize, slab: usize) {
+        self.unlink_partial(index, slab);
+        self.free_blocks[index] -= Self::blocks_per_slab(index);
+
+        let slab_ptr = NonNull::new(self.slab_start(slab) as *mut u8).unwrap();
+        self.fallback_allocator.deallocate(slab_ptr, Self::slab_layout());
+    }
+}
+
+impl AllocatorStats for FixedSizeBlockAllocator {
+    fn stats(&self) -> HeapStats {
+        let fallback = self.fallback_allocator.stats();
+        HeapStats {
+            heap_size: fallback.heap_size,
+            bytes_allocated: self.counters.bytes_allocated,
+            peak_bytes_allocated: self.counters.peak_bytes_allocated,
+            allocations: self.counters.allocations,
+            deallocations: self.counters.deallocations,
+            free_list_lengths: Some(self.free_blocks),
+            free_bytes: fallback.free_bytes,
+            largest_free_block: fallback.largest_free_block,
+        }
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
-                // remove first node
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        // link head to second node
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        // no block exists in the list
-                        let block_size = BLOCK_SIZES[index];
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
-
-                        allocator.fallback_alloc(layout)
-                    }
+                // no slab has a free block, get a new one
+                if allocator.free_blocks[index] == 0 && allocator.refill(index).is_err() {
+                    return ptr::null_mut();
                 }
+                allocator.pop_block(index).unwrap()
             }
             None => allocator.fallback_alloc(layout)
+        };
+
+        if !ptr.is_null() {
+            allocator.counters.record_alloc(layout.size());
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.counters.record_dealloc(layout.size());
         match list_index(&layout) {
-            Some(index) => {
-                // create new block
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take()
-                };
-
-                // assert that the new node have the correct size / alignment to store a `ListNode`
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                // set head of the list (which is None bacause we used take()) to the new node
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
+            Some(index) => allocator.push_block(index, ptr),
             None => {
                 // allocation created by `fallback_allocator`
                 let ptr = NonNull::new(ptr).unwrap();
@@ -85,6 +291,33 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             }
         }
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let in_place = {
+            let mut allocator = self.lock();
+            let in_place = match (list_index(&layout), list_index(&new_layout)) {
+                // the block is already large enough
+                (Some(index), Some(new_index)) => index == new_index,
+                (None, None) => {
+                    let ptr = NonNull::new(ptr).unwrap();
+                    allocator.fallback_allocator.realloc_in_place(ptr, layout, new_size)
+                }
+                // moving between a block list and the fallback allocator
+                _ => false,
+            };
+            if in_place {
+                allocator.counters.record_realloc(layout.size(), new_size);
+            }
+            in_place
+        };
+
+        if in_place {
+            ptr
+        } else {
+            realloc_by_copy(self, ptr, layout, new_size)
+        }
+    }
 }
 
 fn list_index(layout: &Layout) -> Option<usize> {
@@ -92,4 +325,4 @@ fn list_index(layout: &Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
     // find index of the first block at least as large as `required_block_size`
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
-}
\ No newline at end of file
+}
@@ -1,6 +1,7 @@
-use super::{align_up, Locked};
+use super::{align_up, realloc_by_copy, Locked};
+use super::stats::{AllocatorStats, Counters, HeapStats};
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::{mem, ptr};
+use core::{mem, ptr, ptr::NonNull};
 
 
 struct ListNode {
@@ -23,36 +24,161 @@ impl ListNode {
 }
 
 pub struct LinkedListAllocator {
-    head: ListNode
+    head: ListNode,
+    heap_size: usize,
+    counters: Counters,
 }
 
 impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
-            head: ListNode::new(0)
+            head: ListNode::new(0),
+            heap_size: 0,
+            counters: Counters::new(),
         }
     }
 
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_size += heap_size;
         self.add_free_region(heap_start, heap_size);
     }
 
+    // same interface as `linked_list_allocator::Heap`, so it can be used as a fallback allocator
+    pub fn allocate_first_fit(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        let (size, align) = Self::size_align(layout);
+        let (region, alloc_start) = self.find_region(size, align).ok_or(())?;
+        let region_start = region.start_addr();
+        let region_end = region.end_addr();
+
+        let alloc_end = alloc_start.checked_add(size).expect("overflow");
+        let front_padding = alloc_start - region_start;
+        let excess_size = region_end - alloc_end;
+        unsafe {
+            // give back the memory skipped to align the allocation
+            if front_padding > 0 {
+                self.add_free_region(region_start, front_padding);
+            }
+            if excess_size > 0 {
+                self.add_free_region(alloc_end, excess_size);
+            }
+        }
+
+        self.counters.record_alloc(layout.size());
+        Ok(unsafe { NonNull::new_unchecked(alloc_start as *mut u8) })
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        // add deallocated region to the free list
+        self.add_free_region(ptr.as_ptr() as usize, size);
+        self.counters.record_dealloc(layout.size());
+    }
+
+    // resize the allocation without moving it, by taking memory from the free region that follows it
+    // (or giving back its end when shrinking), return false if it's not possible
+    pub unsafe fn realloc_in_place(&mut self, ptr: NonNull<u8>, layout: Layout, new_size: usize) -> bool {
+        let (size, _) = Self::size_align(layout);
+        let (new_size_aligned, _) = Self::size_align(Layout::from_size_align_unchecked(new_size, layout.align()));
+        let start = ptr.as_ptr() as usize;
+        let end = start + size;
+        let new_end = start + new_size_aligned;
+
+        if new_end <= end {
+            let excess_size = end - new_end;
+            if excess_size > 0 {
+                if excess_size < mem::size_of::<ListNode>() {
+                    // the end of the block couldn't be added back to the free list
+                    return false;
+                }
+                self.add_free_region(new_end, excess_size);
+            }
+        } else {
+            // find the free region starting at the end of the allocation
+            let mut current = &mut self.head;
+            while current.next.as_ref().map_or(false, |next| next.start_addr() < end) {
+                current = current.next.as_mut().unwrap();
+            }
+
+            let region_end = match current.next.as_ref() {
+                Some(region) if region.start_addr() == end && region.end_addr() >= new_end => region.end_addr(),
+                _ => return false,
+            };
+            let excess_size = region_end - new_end;
+            if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+                // rest of the region too small to hold a ListNode
+                return false;
+            }
+
+            // remove the region from the list and give back what we don't need
+            let region = current.next.take().unwrap();
+            current.next = region.next.take();
+            if excess_size > 0 {
+                self.add_free_region(new_end, excess_size);
+            }
+        }
+
+        self.counters.record_realloc(layout.size(), new_size);
+        true
+    }
+
+    // total size of the free regions and size of the largest one
+    fn free_regions(&self) -> (usize, usize) {
+        let mut free_bytes = 0;
+        let mut largest_free_block = 0;
+
+        let mut current = &self.head.next;
+        while let Some(region) = current {
+            free_bytes += region.size;
+            largest_free_block = largest_free_block.max(region.size);
+            current = &region.next;
+        }
+
+        (free_bytes, largest_free_block)
+    }
+
     // add a new free memory region to the list
+    // the list is sorted by address so that adjacent regions can be merged
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // ensure that the memory region is capable of holding ListNode
         assert!(align_up(addr, mem::align_of::<ListNode>()) == addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // create a new node
-        let mut node = ListNode::new(size);
-        // node.next = self.head.next
-        // self.head.next = None
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        // write `node` in the `addr` memory region (because `node` is currently on the stack)
-        node_ptr.write(node);
-        // link `node` with `head`
-        self.head.next = Some(&mut *node_ptr);
+        let head_addr = self.head.start_addr();
+
+        // find the last region starting before `addr` (or the head if there is none)
+        let mut current = &mut self.head;
+        while current.next.as_ref().map_or(false, |next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
+
+        if current.start_addr() != head_addr && current.end_addr() == addr {
+            // the previous region ends where the new one starts, extend it
+            current.size += size;
+            Self::merge_with_next(current);
+        } else {
+            // create a new node
+            let mut node = ListNode::new(size);
+            // insert it between `current` and `current.next`
+            node.next = current.next.take();
+            let node_ptr = addr as *mut ListNode;
+            // write `node` in the `addr` memory region (because `node` is currently on the stack)
+            node_ptr.write(node);
+            // merge after the write, `end_addr()` uses the address of the node
+            Self::merge_with_next(&mut *node_ptr);
+            current.next = Some(&mut *node_ptr);
+        }
+    }
+
+    // merge `node` with the next region if they are adjacent
+    fn merge_with_next(node: &mut ListNode) {
+        let end_addr = node.end_addr();
+        match node.next.take() {
+            Some(next) if next.start_addr() == end_addr => {
+                node.size += next.size;
+                node.next = next.next.take();
+            }
+            next => node.next = next,
+        }
     }
 
     fn find_region(&mut self, size: usize, align: usize)
@@ -82,7 +208,15 @@ impl LinkedListAllocator {
     fn alloc_from_region(region: &ListNode, size: usize, align: usize)
         -> Result<usize, ()>
     {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+
+        let front_padding = alloc_start - region.start_addr();
+        if front_padding > 0 && front_padding < mem::size_of::<ListNode>() {
+            // the memory skipped for the alignment is too small to hold a ListNode
+            // so it couldn't be added back to the free list, use the next aligned address instead
+            alloc_start = alloc_start.checked_add(align).ok_or(())?;
+        }
+
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -117,27 +251,41 @@ impl LinkedListAllocator {
     }
 }
 
+impl AllocatorStats for LinkedListAllocator {
+    fn stats(&self) -> HeapStats {
+        let (free_bytes, largest_free_block) = self.free_regions();
+        HeapStats {
+            heap_size: self.heap_size,
+            bytes_allocated: self.counters.bytes_allocated,
+            peak_bytes_allocated: self.counters.peak_bytes_allocated,
+            allocations: self.counters.allocations,
+            deallocations: self.counters.deallocations,
+            free_list_lengths: None,
+            free_bytes,
+            largest_free_block,
+        }
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let (size, align) = LinkedListAllocator::size_align(layout);
-        let mut allocator = self.lock();
-
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size);
-            }
-            alloc_start as *mut u8
-        }
-        else {
-            ptr::null_mut()
+        match self.lock().allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let (size, _) = LinkedListAllocator::size_align(layout);
-        // add deallocated region to the free list
-        self.lock().add_free_region(ptr as usize, size)
+        let ptr = NonNull::new(ptr).unwrap();
+        self.lock().deallocate(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let in_place = self.lock().realloc_in_place(NonNull::new(ptr).unwrap(), layout, new_size);
+        if in_place {
+            ptr
+        } else {
+            realloc_by_copy(self, ptr, layout, new_size)
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,348 @@
+use super::fixed_size_block::SLAB_SIZE;
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::{
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+/* kmem_cache-style allocator for objects of a single type
+    - objects live in slabs of `SLAB_SIZE` bytes, allocated from the global heap only when the cache is empty
+    - each slab starts with a `SlabHeader`, followed by the objects
+    - a bitmap in the header marks the free slots, so a free object can stay in its constructed state
+    - constructors and destructors run without holding the lock of the cache, they can use it
+    - the executor doesn't use it: its wakers must be `Arc`s to become a `Waker`, and the tasks are
+      futures of different sizes
+*/
+
+// maximum number of objects in a slab (number of bits in `SlabHeader::free_map`)
+const MAX_OBJECTS_PER_SLAB: usize = 512;
+
+struct SlabHeader {
+    next: Option<NonNull<SlabHeader>>,
+    free_objects: usize,
+    // bit set = slot free
+    free_map: [u64; MAX_OBJECTS_PER_SLAB / 64],
+}
+
+struct CacheInner {
+    slabs: Option<NonNull<SlabHeader>>,
+    slab_count: usize,
+    objects_in_use: usize,
+    allocations: usize,
+    frees: usize,
+}
+
+// the slabs are only accessed while holding the lock
+unsafe impl Send for CacheInner {}
+
+pub struct ObjectCache<T> {
+    name: &'static str,
+    // objects are built when their slab is created, and kept constructed while they are free
+    constructor: Option<fn() -> T>,
+    // called on an object right before it is dropped
+    destructor: Option<fn(&mut T)>,
+    inner: spin::Mutex<CacheInner>,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Sync for ObjectCache<T> {}
+unsafe impl<T: Send> Send for ObjectCache<T> {}
+
+impl<T> ObjectCache<T> {
+    // objects are created with `alloc_with` and dropped when freed
+    pub const fn new(name: &'static str) -> Self {
+        Self::build(name, None, None)
+    }
+
+    // objects are created by `constructor` and must be given back in their constructed state,
+    // they are only dropped when their slab is released by `shrink`
+    pub const fn with_constructor(
+        name: &'static str,
+        constructor: fn() -> T,
+        destructor: Option<fn(&mut T)>,
+    ) -> Self {
+        Self::build(name, Some(constructor), destructor)
+    }
+
+    const fn build(
+        name: &'static str,
+        constructor: Option<fn() -> T>,
+        destructor: Option<fn(&mut T)>,
+    ) -> Self {
+        ObjectCache {
+            name,
+            constructor,
+            destructor,
+            inner: spin::Mutex::new(CacheInner {
+                slabs: None,
+                slab_count: 0,
+                objects_in_use: 0,
+                allocations: 0,
+                frees: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    // get a constructed object, only for caches created with `with_constructor`
+    pub fn alloc(&self) -> Option<CacheBox<T>> {
+        assert!(self.constructor.is_some(), "object cache `{}` has no constructor", self.name);
+        let ptr = self.alloc_slot()?;
+        Some(CacheBox { cache: self, ptr })
+    }
+
+    // move `value` into the cache
+    pub fn alloc_with(&self, value: T) -> Option<CacheBox<T>> {
+        let ptr = self.alloc_slot()?;
+        unsafe {
+            if self.constructor.is_some() {
+                // replace the constructed object
+                *ptr.as_ptr() = value;
+            } else {
+                ptr.as_ptr().write(value);
+            }
+        }
+        Some(CacheBox { cache: self, ptr })
+    }
+
+    // release the slabs without any object in use, return the number of bytes given back to the heap
+    pub fn shrink(&self) -> usize {
+        let objects_per_slab = Self::objects_per_slab();
+
+        // rebuild the list without the empty slabs, which are linked in `empty`
+        let mut empty = None;
+        let mut released = 0;
+        {
+            let mut inner = self.inner.lock();
+            let mut remaining = inner.slabs.take();
+            let mut kept = None;
+            while let Some(slab_ptr) = remaining {
+                let slab = unsafe { &mut *slab_ptr.as_ptr() };
+                remaining = slab.next.take();
+                if slab.free_objects == objects_per_slab {
+                    slab.next = empty;
+                    empty = Some(slab_ptr);
+                    released += 1;
+                } else {
+                    slab.next = kept;
+                    kept = Some(slab_ptr);
+                }
+            }
+            inner.slabs = kept;
+            inner.slab_count -= released;
+        }
+
+        // the destructors run without the lock
+        while let Some(slab_ptr) = empty {
+            unsafe {
+                empty = (*slab_ptr.as_ptr()).next;
+                self.release_slab(slab_ptr);
+            }
+        }
+        released * SLAB_SIZE
+    }
+
+    pub fn stats(&self) -> ObjectCacheStats {
+        let inner = self.inner.lock();
+        ObjectCacheStats {
+            name: self.name,
+            object_size: mem::size_of::<T>(),
+            objects_per_slab: Self::objects_per_slab(),
+            slabs: inner.slab_count,
+            objects_in_use: inner.objects_in_use,
+            allocations: inner.allocations,
+            frees: inner.frees,
+        }
+    }
+
+    // offset of the first object in a slab
+    fn objects_offset() -> usize {
+        let align = mem::align_of::<T>();
+        (mem::size_of::<SlabHeader>() + align - 1) & !(align - 1)
+    }
+
+    fn objects_per_slab() -> usize {
+        // size_of is always a multiple of align_of, so the objects can be stored next to each other
+        (SLAB_SIZE.saturating_sub(Self::objects_offset()) / mem::size_of::<T>()).min(MAX_OBJECTS_PER_SLAB)
+    }
+
+    fn slab_layout() -> Layout {
+        Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+    }
+
+    fn object_ptr(slab: NonNull<SlabHeader>, slot: usize) -> *mut T {
+        let addr = slab.as_ptr() as usize + Self::objects_offset() + slot * mem::size_of::<T>();
+        addr as *mut T
+    }
+
+    // find a free slot, allocating a new slab if every slab is full
+    fn alloc_slot(&self) -> Option<NonNull<T>> {
+        loop {
+            if let Some(ptr) = self.take_free_slot() {
+                return Some(ptr);
+            }
+
+            // the new slab is built without the lock, the constructor can use the cache
+            let slab_ptr = self.new_slab()?;
+            let mut inner = self.inner.lock();
+            unsafe { (*slab_ptr.as_ptr()).next = inner.slabs.take() };
+            inner.slabs = Some(slab_ptr);
+            inner.slab_count += 1;
+        }
+    }
+
+    // None if every slab is full
+    fn take_free_slot(&self) -> Option<NonNull<T>> {
+        let mut inner = self.inner.lock();
+
+        let mut current = inner.slabs;
+        let slab_ptr = loop {
+            let slab_ptr = current?;
+            let slab = unsafe { slab_ptr.as_ref() };
+            if slab.free_objects > 0 {
+                break slab_ptr;
+            }
+            current = slab.next;
+        };
+
+        let slab = unsafe { &mut *slab_ptr.as_ptr() };
+        let (word_index, word) = slab.free_map.iter_mut()
+            .enumerate()
+            .find(|(_, word)| **word != 0)
+            .expect("slab free count and free map are inconsistent");
+        let bit = word.trailing_zeros() as usize;
+        *word &= !(1 << bit);
+        slab.free_objects -= 1;
+
+        inner.objects_in_use += 1;
+        inner.allocations += 1;
+        NonNull::new(Self::object_ptr(slab_ptr, word_index * 64 + bit))
+    }
+
+    unsafe fn free(&self, ptr: NonNull<T>) {
+        if self.constructor.is_none() {
+            self.destroy(ptr.as_ptr());
+        }
+
+        // slabs are aligned on their size
+        let slab_ptr = (ptr.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+        let slot = (ptr.as_ptr() as usize - slab_ptr as usize - Self::objects_offset()) / mem::size_of::<T>();
+
+        let mut inner = self.inner.lock();
+        let slab = &mut *slab_ptr;
+        slab.free_map[slot / 64] |= 1 << (slot % 64);
+        slab.free_objects += 1;
+
+        inner.objects_in_use -= 1;
+        inner.frees += 1;
+    }
+
+    unsafe fn destroy(&self, object: *mut T) {
+        if let Some(destructor) = self.destructor {
+            destructor(&mut *object);
+        }
+        ptr::drop_in_place(object);
+    }
+
+    fn new_slab(&self) -> Option<NonNull<SlabHeader>> {
+        assert!(mem::size_of::<T>() > 0, "object cache `{}` can't hold zero sized types", self.name);
+        assert!(Self::objects_per_slab() > 0, "objects of cache `{}` don't fit in a slab", self.name);
+
+        let slab_ptr = NonNull::new(unsafe { alloc(Self::slab_layout()) } as *mut SlabHeader)?;
+        let objects_per_slab = Self::objects_per_slab();
+
+        let mut free_map = [0; MAX_OBJECTS_PER_SLAB / 64];
+        for slot in 0..objects_per_slab {
+            free_map[slot / 64] |= 1 << (slot % 64);
+        }
+        unsafe {
+            slab_ptr.as_ptr().write(SlabHeader {
+                next: None,
+                free_objects: objects_per_slab,
+                free_map,
+            });
+
+            if let Some(constructor) = self.constructor {
+                for slot in 0..objects_per_slab {
+                    Self::object_ptr(slab_ptr, slot).write(constructor());
+                }
+            }
+        }
+
+        Some(slab_ptr)
+    }
+
+    unsafe fn release_slab(&self, slab_ptr: NonNull<SlabHeader>) {
+        // objects of caches with a constructor are still constructed
+        if self.constructor.is_some() {
+            for slot in 0..Self::objects_per_slab() {
+                self.destroy(Self::object_ptr(slab_ptr, slot));
+            }
+        }
+        dealloc(slab_ptr.as_ptr() as *mut u8, Self::slab_layout());
+    }
+}
+
+impl<T> Drop for ObjectCache<T> {
+    fn drop(&mut self) {
+        // every `CacheBox` borrows the cache, so all the slabs are empty here
+        self.shrink();
+    }
+}
+
+// owned object from an `ObjectCache`, given back to the cache when dropped
+pub struct CacheBox<'a, T> {
+    cache: &'a ObjectCache<T>,
+    ptr: NonNull<T>,
+}
+
+impl<T> Deref for CacheBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for CacheBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for CacheBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.cache.free(self.ptr) };
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CacheBox<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+unsafe impl<T: Send> Send for CacheBox<'_, T> {}
+unsafe impl<T: Sync> Sync for CacheBox<'_, T> {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ObjectCacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub allocations: usize,
+    pub frees: usize,
+}
+
+impl fmt::Display for ObjectCacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} objects in use ({} bytes each), {} slabs of {} objects, {} allocations, {} frees",
+            self.name, self.objects_in_use, self.object_size, self.slabs, self.objects_per_slab,
+            self.allocations, self.frees)
+    }
+}
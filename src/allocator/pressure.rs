@@ -0,0 +1,98 @@
+use crate::smp::{self, MAX_CPUS};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+/* memory pressure callbacks
+
+   when the heap allocator can't satisfy an allocation, the registered callbacks are asked to
+   release memory (shrink caches, drop spare buffers, tell tasks to back off...) and the
+   allocation is retried once before giving up
+
+   callbacks run in the context of the failing allocation, so they must not block on a lock
+   that might be held while allocating (use `try_lock`), and with interrupts disabled
+   allocations failing on several CPUs at the same time run them concurrently
+*/
+
+// called with the layout that couldn't be allocated, returns the number of bytes released
+pub type PressureCallback = fn(Layout) -> usize;
+
+const MAX_CALLBACKS: usize = 16;
+
+static CALLBACKS: spin::Mutex<[Option<PressureCallback>; MAX_CALLBACKS]> = spin::Mutex::new([None; MAX_CALLBACKS]);
+// set while the callbacks run on each CPU, an allocation failing in a callback doesn't run them again
+// only changed with interrupts disabled, so it is never seen set by an interrupt handler or
+// another thread
+static RELIEVING: [AtomicBool; MAX_CPUS] = [AtomicBool::new(false); MAX_CPUS];
+static PRESSURE_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallbackId(usize);
+
+pub fn register(callback: PressureCallback) -> Result<CallbackId, ()> {
+    let mut callbacks = CALLBACKS.lock();
+    let index = callbacks.iter().position(|c| c.is_none()).ok_or(())?;
+    callbacks[index] = Some(callback);
+    Ok(CallbackId(index))
+}
+
+pub fn unregister(id: CallbackId) {
+    CALLBACKS.lock()[id.0] = None;
+}
+
+// number of allocations that failed and ran the callbacks
+pub fn pressure_events() -> usize {
+    PRESSURE_EVENTS.load(Ordering::Relaxed)
+}
+
+// run every callback, return the number of bytes they released
+fn relieve(layout: Layout) -> usize {
+    interrupts::without_interrupts(|| {
+        let relieving = &RELIEVING[smp::cpu_index()];
+        if relieving.swap(true, Ordering::Acquire) {
+            return 0;
+        }
+        PRESSURE_EVENTS.fetch_add(1, Ordering::Relaxed);
+
+        // don't hold the lock while the callbacks run, they may allocate or (un)register
+        let callbacks = *CALLBACKS.lock();
+        let released = callbacks.iter().flatten().map(|callback| callback(layout)).sum();
+
+        relieving.store(false, Ordering::Release);
+        released
+    })
+}
+
+// wrapper around the heap allocator retrying failed allocations after running the callbacks
+pub struct PressureAllocator<A: 'static> {
+    inner: &'static A,
+}
+
+impl<A: GlobalAlloc> PressureAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        PressureAllocator { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for PressureAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() && relieve(layout) > 0 {
+            return self.inner.alloc(layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // on failure the block is left untouched, so it can be retried
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() && relieve(Layout::from_size_align_unchecked(new_size, layout.align())) > 0 {
+            return self.inner.realloc(ptr, layout, new_size);
+        }
+        new_ptr
+    }
+}
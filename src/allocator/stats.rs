@@ -0,0 +1,76 @@
+use super::fixed_size_block::BLOCK_SIZES;
+use core::fmt;
+
+// snapshot of the state of a heap allocator, returned by `Locked::stats`
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub heap_size: usize,
+    // bytes requested by the live allocations (not the size of the blocks backing them)
+    pub bytes_allocated: usize,
+    pub peak_bytes_allocated: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+    // number of free blocks in each `BLOCK_SIZES` list (only for `FixedSizeBlockAllocator`)
+    pub free_list_lengths: Option<[usize; BLOCK_SIZES.len()]>,
+    // free space left in the heap (in the fallback heap for `FixedSizeBlockAllocator`)
+    pub free_bytes: usize,
+    pub largest_free_block: usize,
+}
+
+pub trait AllocatorStats {
+    fn stats(&self) -> HeapStats;
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap: {} / {} bytes allocated (peak {})",
+            self.bytes_allocated, self.heap_size, self.peak_bytes_allocated)?;
+        writeln!(f, "allocations: {}, deallocations: {}", self.allocations, self.deallocations)?;
+        write!(f, "free: {} bytes, largest free block: {} bytes", self.free_bytes, self.largest_free_block)?;
+
+        if let Some(lengths) = self.free_list_lengths {
+            write!(f, "\nfree blocks per size class:")?;
+            for (block_size, length) in BLOCK_SIZES.iter().zip(lengths.iter()) {
+                write!(f, "\n  {:>5}: {}", block_size, length)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// counters updated by the allocators on each alloc / dealloc
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Counters {
+    pub bytes_allocated: usize,
+    pub peak_bytes_allocated: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+}
+
+impl Counters {
+    pub const fn new() -> Self {
+        Counters {
+            bytes_allocated: 0,
+            peak_bytes_allocated: 0,
+            allocations: 0,
+            deallocations: 0,
+        }
+    }
+
+    pub fn record_alloc(&mut self, size: usize) {
+        self.bytes_allocated += size;
+        self.peak_bytes_allocated = self.peak_bytes_allocated.max(self.bytes_allocated);
+        self.allocations += 1;
+    }
+
+    pub fn record_dealloc(&mut self, size: usize) {
+        self.bytes_allocated -= size;
+        self.deallocations += 1;
+    }
+
+    // allocation resized in place
+    pub fn record_realloc(&mut self, old_size: usize, new_size: usize) {
+        self.bytes_allocated = self.bytes_allocated - old_size + new_size;
+        self.peak_bytes_allocated = self.peak_bytes_allocated.max(self.bytes_allocated);
+    }
+}
@@ -0,0 +1,266 @@
+use crate::serial_println;
+use alloc::{collections::BTreeMap, vec::Vec};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+
+/* wrapper around the global allocator used with the `heap_track` feature
+
+   every live allocation is recorded with its size, a timestamp (TSC) and the return addresses
+   found by following the frame pointers from the frame of `alloc`, so that the backtrace starts at
+   its caller and not in the tracker
+   records are kept in a static table because the tracker can't allocate from the heap it tracks
+
+   the frame pointers are only kept when building for the `x86_64-rust_os-heap_track.json` target
+   (`eliminate-frame-pointer: false`), the other builds don't pay for them
+*/
+
+// number of return addresses recorded for each allocation
+pub const BACKTRACE_DEPTH: usize = 8;
+const MAX_TRACKED: usize = 512;
+
+pub type Backtrace = [usize; BACKTRACE_DEPTH];
+
+#[derive(Clone, Copy)]
+struct Record {
+    // 0 = free slot
+    ptr: usize,
+    size: usize,
+    timestamp: u64,
+    backtrace: Backtrace,
+}
+
+impl Record {
+    const EMPTY: Record = Record {
+        ptr: 0,
+        size: 0,
+        timestamp: 0,
+        backtrace: [0; BACKTRACE_DEPTH],
+    };
+}
+
+struct Records {
+    records: [Record; MAX_TRACKED],
+    // allocations not recorded because the table was full
+    untracked: usize,
+}
+
+pub struct TrackingAllocator<A: 'static> {
+    inner: &'static A,
+    records: super::Locked<Records>,
+}
+
+impl<A: GlobalAlloc> TrackingAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        TrackingAllocator {
+            inner,
+            records: super::Locked::new(Records {
+                records: [Record::EMPTY; MAX_TRACKED],
+                untracked: 0,
+            }),
+        }
+    }
+
+    // `frame`: frame pointer of `alloc` / `realloc`
+    fn record(&self, ptr: *mut u8, size: usize, frame: usize) {
+        let record = Record {
+            ptr: ptr as usize,
+            size,
+            timestamp: timestamp(),
+            backtrace: backtrace(frame),
+        };
+
+        let mut records = self.records.lock();
+        match records.records.iter_mut().find(|r| r.ptr == 0) {
+            Some(slot) => *slot = record,
+            None => records.untracked += 1,
+        }
+    }
+
+    fn forget(&self, ptr: *mut u8) {
+        let mut records = self.records.lock();
+        // not found if it was allocated while the table was full
+        if let Some(slot) = records.records.iter_mut().find(|r| r.ptr == ptr as usize) {
+            *slot = Record::EMPTY;
+        }
+    }
+
+    // group the live allocations by backtrace
+    pub fn snapshot(&self) -> Snapshot {
+        // allocate before taking the lock, `alloc` needs it
+        let count = self.records.lock().records.iter().filter(|r| r.ptr != 0).count();
+        // some room for the allocations made in between
+        let mut live = Vec::with_capacity(count + 16);
+        let untracked = {
+            let records = self.records.lock();
+            for record in records.records.iter().filter(|r| r.ptr != 0) {
+                // never grow the vec while holding the lock
+                if live.len() == live.capacity() {
+                    break;
+                }
+                live.push(*record);
+            }
+            records.untracked
+        };
+
+        let mut sites: BTreeMap<Backtrace, CallSite> = BTreeMap::new();
+        for record in live {
+            let site = sites.entry(record.backtrace).or_insert(CallSite {
+                allocations: 0,
+                bytes: 0,
+                oldest: record.timestamp,
+            });
+            site.allocations += 1;
+            site.bytes += record.size;
+            site.oldest = site.oldest.min(record.timestamp);
+        }
+
+        Snapshot {
+            timestamp: timestamp(),
+            sites,
+            untracked,
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let frame = frame_pointer();
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            self.record(ptr, layout.size(), frame);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.forget(ptr);
+        self.inner.dealloc(ptr, layout);
+    }
+
+    // forward to the inner allocator so that it can resize in place
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let frame = frame_pointer();
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            self.forget(ptr);
+            self.record(new_ptr, new_size, frame);
+        }
+        new_ptr
+    }
+}
+
+fn timestamp() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+// rbp of the calling function
+#[inline(always)]
+fn frame_pointer() -> usize {
+    let rbp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+    rbp
+}
+
+// follow the saved rbp chain from `rbp` and collect the return addresses
+// the first one is in the caller of the function owning the frame
+// the walk stops at the first frame outside of the current stack, so a broken chain (or a build
+// without frame pointers) gives a short backtrace instead of reading arbitrary memory
+fn backtrace(mut rbp: usize) -> Backtrace {
+    let stack = crate::smp::current_stack();
+    let mut backtrace = [0; BACKTRACE_DEPTH];
+    for return_address in backtrace.iter_mut() {
+        // frame layout: [rbp] = caller rbp, [rbp + 8] = return address
+        if rbp % 8 != 0 || rbp < stack.start || rbp + 16 > stack.end {
+            break;
+        }
+        let (caller_rbp, address) = unsafe { (*(rbp as *const usize), *((rbp + 8) as *const usize)) };
+        *return_address = address;
+
+        // the stack grows downwards, so the caller frame is above the current one
+        if caller_rbp <= rbp {
+            break;
+        }
+        rbp = caller_rbp;
+    }
+    backtrace
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CallSite {
+    pub allocations: usize,
+    pub bytes: usize,
+    // timestamp of the oldest live allocation
+    pub oldest: u64,
+}
+
+// live allocations grouped by backtrace at a given time
+pub struct Snapshot {
+    pub timestamp: u64,
+    pub sites: BTreeMap<Backtrace, CallSite>,
+    pub untracked: usize,
+}
+
+impl Snapshot {
+    // call sites that have more live bytes in `later` than in `self`
+    pub fn diff(&self, later: &Snapshot) -> Vec<SiteGrowth> {
+        let mut growth: Vec<SiteGrowth> = later.sites.iter()
+            .map(|(backtrace, site)| {
+                let (allocations, bytes) = self.sites.get(backtrace)
+                    .map_or((0, 0), |before| (before.allocations, before.bytes));
+                SiteGrowth {
+                    backtrace: *backtrace,
+                    allocations: site.allocations as isize - allocations as isize,
+                    bytes: site.bytes as isize - bytes as isize,
+                }
+            })
+            .filter(|growth| growth.bytes > 0)
+            .collect();
+
+        growth.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+        growth
+    }
+
+    // print the report over serial, the VGA buffer is too small for it
+    pub fn dump(&self) {
+        serial_println!("{}", self);
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sites: Vec<_> = self.sites.iter().collect();
+        sites.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes));
+
+        write!(f, "live allocations: {} call sites", sites.len())?;
+        if self.untracked > 0 {
+            write!(f, " ({} allocations not tracked, table full)", self.untracked)?;
+        }
+        for (backtrace, site) in sites {
+            write!(f, "\n{} bytes in {} allocations, oldest {} cycles ago\n  ",
+                site.bytes, site.allocations, self.timestamp - site.oldest)?;
+            write_backtrace(f, backtrace)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SiteGrowth {
+    pub backtrace: Backtrace,
+    pub allocations: isize,
+    pub bytes: isize,
+}
+
+impl fmt::Display for SiteGrowth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "+{} bytes in {:+} allocations\n  ", self.bytes, self.allocations)?;
+        write_backtrace(f, &self.backtrace)
+    }
+}
+
+fn write_backtrace(f: &mut fmt::Formatter, backtrace: &Backtrace) -> fmt::Result {
+    for address in backtrace.iter().take_while(|&&address| address != 0) {
+        write!(f, "{:#x} ", address)?;
+    }
+    Ok(())
+}
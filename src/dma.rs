@@ -0,0 +1,281 @@
+use crate::memory::BootInfoFrameAllocator;
+use core::{fmt, mem, ptr, slice};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB
+    },
+    PhysAddr, VirtAddr,
+};
+
+/* memory for device drivers: physically contiguous, below 4 GiB, with a known physical address
+
+   a contiguous run of frames is reserved at init and mapped at DMA_START, the first half
+   uncached (for device registers / descriptor rings), the second half write-back
+   buffers are made of whole pages taken from the half matching the requested cache mode
+*/
+
+pub const DMA_START: usize = 0x_5555_5555_0000;
+pub const DMA_POOL_SIZE: usize = 256 * 1024; // 256 KiB, split between the two cache modes
+const PAGE_SIZE: usize = 4096;
+const POOL_PAGES: usize = DMA_POOL_SIZE / PAGE_SIZE / 2;
+// 32-bit devices can only address the first 4 GiB
+const DMA_LIMIT: u64 = 1 << 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    Uncached,
+    WriteBack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    NotInitialized,
+    OutOfMemory,
+}
+
+struct Pool {
+    virt_start: usize,
+    phys_start: u64,
+    used: [bool; POOL_PAGES],
+}
+
+impl Pool {
+    // first fit of `pages` contiguous pages, return the index of the first one
+    fn alloc(&mut self, pages: usize) -> Option<usize> {
+        let mut run = 0;
+        for index in 0..POOL_PAGES {
+            run = if self.used[index] { 0 } else { run + 1 };
+            if run == pages {
+                let first = index + 1 - pages;
+                self.used[first..=index].iter_mut().for_each(|used| *used = true);
+                return Some(first);
+            }
+        }
+        None
+    }
+
+    fn free(&mut self, first: usize, pages: usize) {
+        self.used[first..first + pages].iter_mut().for_each(|used| *used = false);
+    }
+}
+
+// [uncached pool, write-back pool]
+static POOLS: spin::Mutex<Option<[Pool; 2]>> = spin::Mutex::new(None);
+
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut BootInfoFrameAllocator
+) -> Result<(), MapToError<Size4KiB>> {
+    let pages = DMA_POOL_SIZE / PAGE_SIZE;
+    let first_frame = frame_allocator.allocate_contiguous(pages, PhysAddr::new(DMA_LIMIT))
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(DMA_START as u64));
+
+    for i in 0..pages {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if i < POOL_PAGES {
+            flags |= PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        }
+        let frame: PhysFrame = first_frame + i as u64;
+        unsafe { mapper.map_to(first_page + i as u64, frame, flags, frame_allocator)?.flush() };
+    }
+
+    let phys_start = first_frame.start_address().as_u64();
+    let pool_size = POOL_PAGES * PAGE_SIZE;
+    *POOLS.lock() = Some([
+        Pool { virt_start: DMA_START, phys_start, used: [false; POOL_PAGES] },
+        Pool { virt_start: DMA_START + pool_size, phys_start: phys_start + pool_size as u64, used: [false; POOL_PAGES] },
+    ]);
+    Ok(())
+}
+
+fn pool_index(mode: CacheMode) -> usize {
+    match mode {
+        CacheMode::Uncached => 0,
+        CacheMode::WriteBack => 1,
+    }
+}
+
+// zeroed contiguous pages holding at least `size` bytes
+struct Region {
+    virt: NonNull<u8>,
+    phys: PhysAddr,
+    first: usize,
+    pages: usize,
+    mode: CacheMode,
+}
+
+impl Region {
+    fn alloc(size: usize, mode: CacheMode) -> Result<Self, DmaError> {
+        let pages = (size.max(1) + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut pools = POOLS.lock();
+        let pool = &mut pools.as_mut().ok_or(DmaError::NotInitialized)?[pool_index(mode)];
+        let first = pool.alloc(pages).ok_or(DmaError::OutOfMemory)?;
+
+        let virt = (pool.virt_start + first * PAGE_SIZE) as *mut u8;
+        // the device shouldn't see what the previous owner left
+        unsafe { ptr::write_bytes(virt, 0, pages * PAGE_SIZE) };
+        Ok(Region {
+            virt: NonNull::new(virt).unwrap(),
+            phys: PhysAddr::new(pool.phys_start + (first * PAGE_SIZE) as u64),
+            first,
+            pages,
+            mode,
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        let mut pools = POOLS.lock();
+        pools.as_mut().unwrap()[pool_index(self.mode)].free(self.first, self.pages);
+    }
+}
+
+// a single value in DMA memory
+pub struct DmaBuffer<T> {
+    region: Region,
+    ptr: NonNull<T>,
+}
+
+// the buffer owns its region, like a Box
+unsafe impl<T: Send> Send for DmaBuffer<T> {}
+unsafe impl<T: Sync> Sync for DmaBuffer<T> {}
+
+impl<T> DmaBuffer<T> {
+    pub fn new(value: T, mode: CacheMode) -> Result<Self, DmaError> {
+        // buffers start on a page boundary
+        assert!(mem::align_of::<T>() <= PAGE_SIZE);
+        let region = Region::alloc(mem::size_of::<T>(), mode)?;
+        let ptr = region.virt.cast::<T>();
+        unsafe { ptr.as_ptr().write(value) };
+        Ok(DmaBuffer { region, ptr })
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.region.phys
+    }
+
+    pub fn cache_mode(&self) -> CacheMode {
+        self.region.mode
+    }
+}
+
+impl<T> Deref for DmaBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.ptr.as_ptr()) };
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for DmaBuffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DmaBuffer({:?} at {:?})", **self, self.phys_addr())
+    }
+}
+
+// a fixed capacity vector in DMA memory, it never moves so its physical address stays valid
+pub struct DmaVec<T> {
+    region: Region,
+    ptr: NonNull<T>,
+    len: usize,
+    capacity: usize,
+}
+
+unsafe impl<T: Send> Send for DmaVec<T> {}
+unsafe impl<T: Sync> Sync for DmaVec<T> {}
+
+impl<T> DmaVec<T> {
+    pub fn with_capacity(capacity: usize, mode: CacheMode) -> Result<Self, DmaError> {
+        assert!(mem::align_of::<T>() <= PAGE_SIZE);
+        let size = mem::size_of::<T>().checked_mul(capacity).ok_or(DmaError::OutOfMemory)?;
+        let region = Region::alloc(size, mode)?;
+        let ptr = region.virt.cast::<T>();
+        // use all the pages we got
+        let capacity = match mem::size_of::<T>() {
+            0 => usize::MAX,
+            size => region.size() / size,
+        };
+        Ok(DmaVec { region, ptr, len: 0, capacity })
+    }
+
+    // give the value back if the vec is full
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.len == self.capacity {
+            return Err(value);
+        }
+        unsafe { self.ptr.as_ptr().add(self.len).write(value) };
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(unsafe { self.ptr.as_ptr().add(self.len).read() })
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // physical address of the first element
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.region.phys
+    }
+
+    pub fn cache_mode(&self) -> CacheMode {
+        self.region.mode
+    }
+}
+
+impl<T> Deref for DmaVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> DerefMut for DmaVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> Drop for DmaVec<T> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for DmaVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DmaVec({:?} at {:?})", &**self, self.phys_addr())
+    }
+}
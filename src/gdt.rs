@@ -2,6 +2,7 @@ use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use lazy_static::lazy_static;
+use alloc::{boxed::Box, vec};
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
@@ -18,17 +19,17 @@ struct Selectors {
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096;
 
 // double fault stack is at index 0 inthe IST
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
 
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
+            let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
 
             // return top address of the stack, because stack grow downwards
             // (high addresses to low addresses)
@@ -39,14 +40,36 @@ lazy_static! {
 }
 
 pub fn init() {
+    load(&GDT.0, &GDT.1);
+}
+
+/* GDT of an application processor (SMP)
+
+   the busy flag of a TSS descriptor is set when it is loaded, and two CPUs can't share a double
+   fault stack: each CPU gets its own GDT, TSS and IST stack, allocated on the heap and never freed
+*/
+pub fn init_ap() {
+    let mut tss = TaskStateSegment::new();
+    let stack: &'static mut [u8] = Box::leak(vec![0; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    load(gdt, &Selectors { code_selector, tss_selector });
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.load();
     unsafe {
         // reload cs to use our new GDT
-        set_cs(GDT.1.code_selector);
+        set_cs(selectors.code_selector);
         // tell the CPU t use this TSS
-        load_tss(GDT.1.tss_selector);
+        load_tss(selectors.tss_selector);
     }
-}
\ No newline at end of file
+}
@@ -23,6 +23,8 @@ lazy_static! {
 
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[usize::from(APIC_WAKEUP_VECTOR)].set_handler_fn(wakeup_interrupt_handler);
+        idt[usize::from(APIC_SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
     
         idt
     };
@@ -101,11 +103,30 @@ impl InterruptIndex {
     }
 }
 
+// PIT : Programmable Interval Timer, raises the timer interrupt `hz` times per second
+pub fn set_timer_frequency(hz: u32) {
+    use x86_64::instructions::port::Port;
+
+    const PIT_FREQUENCY: u32 = 1_193_182;
+    let divisor = (PIT_FREQUENCY / hz).max(1).min(u32::from(u16::MAX)) as u16;
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        // channel 0, low byte then high byte, rate generator
+        command.write(0x34);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    });
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame)
 {
+    // before a possible switch, the next thread may not come back here for a while
     unsafe {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    // preempt the current thread, it resumes here on its next turn
+    crate::thread::timer_tick();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame)
@@ -120,4 +141,19 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut Interrup
     unsafe {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
-}
\ No newline at end of file
+}
+
+// raised by a local APIC (SMP) for an interrupt it dropped, must not be acknowledged
+pub const APIC_SPURIOUS_VECTOR: u8 = 0xff;
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame)
+{
+}
+
+// sent by another CPU (SMP) to get this one out of `hlt`, the handler has nothing else to do
+pub const APIC_WAKEUP_VECTOR: u8 = 0xf0;
+
+extern "x86-interrupt" fn wakeup_interrupt_handler(_stack_frame: &mut InterruptStackFrame)
+{
+    crate::smp::end_of_interrupt();
+}
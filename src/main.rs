@@ -11,10 +11,12 @@ use bootloader::{BootInfo, entry_point};
 use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
 
 use rust_os::println;
+use rust_os::thread;
 use rust_os::task::{
     Task,
     keyboard,
     executor::Executor,
+    scheduler::Priority,
 };
 
 entry_point!(kernel_main);
@@ -34,6 +36,11 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    rust_os::dma::init(&mut mapper, &mut frame_allocator).expect("DMA pool initialization failed");
+    rust_os::thread::init(&mut mapper, &mut frame_allocator).expect("thread initialization failed");
+    let cpus = rust_os::smp::init(&mut mapper, &mut frame_allocator, phys_mem_offset)
+        .expect("SMP initialization failed");
+    println!("{} CPUs online", cpus);
 
     // map an unused page
     let page = Page::containing_address(VirtAddr::new(0xdeadbeaf000));
@@ -62,15 +69,25 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("new ref count is {}", Rc::strong_count(&cloned_reference));
 
 
+    #[cfg(test)]
+    test_main();
+
+    // CPU bound work runs in its own thread, the executor (in the boot thread) keeps echoing keypresses
+    thread::spawn(|| {
+        let sum: u64 = (0..10_000_000u64).fold(0, |sum, i| sum.wrapping_add(i * i));
+        println!("sum of squares computed in a thread: {}", sum);
+    }).expect("failed to spawn a thread");
+
     // test .wait
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(example_task()).with_name("example"));
+    // keypresses are echoed right away even when other tasks are busy
+    executor.spawn(Task::new(keyboard::print_keypresses())
+        .with_name("keyboard")
+        .with_priority(Priority::High));
+    // returns once a task triggers `executor.shutdown_signal()`
     executor.run();
 
-    #[cfg(test)]
-    test_main();
-
     println!("It didn't crash !");
     rust_os::hlt_loop();
 }
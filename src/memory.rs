@@ -68,9 +68,13 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+// frames below 1 MiB are kept for code which must run in real mode (the SMP trampoline)
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    next_low: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -78,11 +82,25 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            next_low: 0,
         }
     }
 
-    // convert memory_map into an iterator of usables frames
+    // usable frames above LOW_MEMORY_END
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        self.all_usable_frames().filter(|frame| frame.start_address().as_u64() >= LOW_MEMORY_END)
+    }
+
+    // usable frames below LOW_MEMORY_END, apart from the first one (real mode interrupt table)
+    fn low_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        self.all_usable_frames().filter(|frame| {
+            let addr = frame.start_address().as_u64();
+            addr != 0 && addr < LOW_MEMORY_END
+        })
+    }
+
+    // convert memory_map into an iterator of usables frames
+    fn all_usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         let regions = self.memory_map.iter();
         let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
         // map each region to its address space (transform MemoryRegion to address ranges using ..)
@@ -97,6 +115,39 @@ impl BootInfoFrameAllocator {
         // transform to Iterator<Item = PhysFrame>
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    // allocate `count` physically contiguous frames ending below `limit`, return the first one
+    // frames skipped to find a large enough run are never handed out (we can't free frames anyway)
+    pub fn allocate_contiguous(&mut self, count: usize, limit: PhysAddr) -> Option<PhysFrame> {
+        let mut run_start = self.next;
+        let mut run_length = 0;
+        let mut previous: Option<PhysFrame> = None;
+
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            if frame.start_address() + 4096u64 > limit {
+                return None;
+            }
+            if previous.map_or(true, |previous| previous + 1 != frame) {
+                run_start = index;
+                run_length = 0;
+            }
+            previous = Some(frame);
+            run_length += 1;
+
+            if run_length == count {
+                self.next = index + 1;
+                return self.usable_frames().nth(run_start);
+            }
+        }
+        None
+    }
+
+    // a frame below 1 MiB, which a CPU still in real mode can address
+    pub fn allocate_low_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.low_frames().nth(self.next_low);
+        self.next_low += 1;
+        frame
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
@@ -0,0 +1,97 @@
+use core::ptr;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB
+    },
+    PhysAddr, VirtAddr,
+};
+
+/* Local APIC: the interrupt controller of each CPU
+
+   its registers are memory mapped, at the same physical address for every CPU (each CPU sees its
+   own), mapped uncached at LAPIC_START
+   the PIC is still used for the timer and keyboard interrupts of the bootstrap CPU, the APICs are
+   only used to send IPIs (Inter-Processor Interrupts): startup, and wakeup of a halted CPU
+*/
+
+pub const LAPIC_START: usize = 0x_7777_7777_0000;
+
+const ID: usize = 0x20;
+const EOI: usize = 0xb0;
+const SPURIOUS_VECTOR: usize = 0xf0;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+
+// spurious vector register: APIC software enable
+const APIC_ENABLE: u32 = 1 << 8;
+// interrupt command register: the previous IPI wasn't sent yet
+const DELIVERY_PENDING: u32 = 1 << 12;
+// level assert, fixed delivery mode, the vector is in the low byte
+const IPI_FIXED: u32 = 0x4000;
+// level assert, INIT delivery mode
+const IPI_INIT: u32 = 0x4500;
+// level assert, startup delivery mode, the vector is the page number of the start code
+const IPI_STARTUP: u32 = 0x4600;
+
+pub(super) fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys_addr: u64,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page = Page::containing_address(VirtAddr::new(LAPIC_START as u64));
+    let frame = PhysFrame::containing_address(PhysAddr::new(phys_addr));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    Ok(())
+}
+
+fn read(register: usize) -> u32 {
+    unsafe { ptr::read_volatile((LAPIC_START + register) as *const u32) }
+}
+
+fn write(register: usize, value: u32) {
+    unsafe { ptr::write_volatile((LAPIC_START + register) as *mut u32, value) }
+}
+
+// APIC ID of the current CPU
+pub(super) fn id() -> u32 {
+    read(ID) >> 24
+}
+
+// interrupts sent to the APIC while it can't deliver them use the spurious vector
+pub(super) fn enable() {
+    let vector = u32::from(crate::interrupts::APIC_SPURIOUS_VECTOR);
+    write(SPURIOUS_VECTOR, APIC_ENABLE | vector);
+}
+
+// end of interrupt, for the interrupts sent by an APIC (not the spurious ones)
+pub(super) fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+fn send_ipi(apic_id: u32, command: u32) {
+    // an interrupt handler sending an IPI between the two writes would change the destination
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write(ICR_HIGH, apic_id << 24);
+        // writing the low half sends the IPI
+        write(ICR_LOW, command);
+        while read(ICR_LOW) & DELIVERY_PENDING != 0 {}
+    });
+}
+
+pub(super) fn send_fixed(apic_id: u32, vector: u8) {
+    send_ipi(apic_id, IPI_FIXED | u32::from(vector));
+}
+
+pub(super) fn send_init(apic_id: u32) {
+    send_ipi(apic_id, IPI_INIT);
+}
+
+// the CPU starts in real mode at the start of `frame`, which must be below 1 MiB
+pub(super) fn send_startup(apic_id: u32, frame: PhysFrame) {
+    let page_number = (frame.start_address().as_u64() >> 12) as u32;
+    send_ipi(apic_id, IPI_STARTUP | page_number);
+}
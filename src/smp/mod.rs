@@ -0,0 +1,295 @@
+use crate::{acpi, gdt, interrupts, memory::BootInfoFrameAllocator, thread};
+use alloc::{boxed::Box, vec::Vec};
+use core::ops::Range;
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use x86_64::{
+    instructions::interrupts::{self as cpu_interrupts, enable_interrupts_and_hlt},
+    registers::model_specific::Msr,
+    structures::paging::{mapper::MapToError, Mapper, Size4KiB},
+    VirtAddr,
+};
+
+/* symmetric multiprocessing: start the other CPUs
+
+   - the CPUs are listed by the ACPI MADT, the bootstrap CPU (BSP, running `kernel_main`) starts
+     the application processors (APs) one at a time with the INIT-SIPI-SIPI sequence
+   - each AP runs the trampoline up to long mode, loads its own GDT/TSS and the IDT, enables its
+     local APIC, checks in, then halts with interrupts enabled until it is given a job (`run_on`)
+   - the per-CPU data is pointed to by the GS base, its first field is its own address so that
+     `current` only needs `gs:[0]`
+   - the threads keep running on the BSP only, the APs run the SendTasks of the executors
+     (`Executor::run` gives them a job): the code running on an AP must not block, sleep or yield
+     its thread, the thread functions panic there (see `thread`)
+*/
+
+mod lapic;
+mod trampoline;
+
+pub use lapic::LAPIC_START;
+
+// including the bootstrap CPU
+pub const MAX_CPUS: usize = 16;
+const AP_STACK_SIZE: usize = 16 * 1024; // 16 KiB
+const IA32_GS_BASE: u32 = 0xc000_0101;
+
+#[derive(Debug)]
+pub enum SmpError {
+    Acpi(acpi::AcpiError),
+    // no usable frame below 1 MiB for the trampoline
+    NoLowMemory,
+    // the trampoline loads cr3 in 32-bit mode
+    PageTableAbove4GiB,
+    MapFailed(MapToError<Size4KiB>),
+}
+
+impl From<acpi::AcpiError> for SmpError {
+    fn from(error: acpi::AcpiError) -> Self {
+        SmpError::Acpi(error)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for SmpError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        SmpError::MapFailed(error)
+    }
+}
+
+// code run by an AP, see `run_on`
+pub type Job = Box<dyn FnOnce() + Send>;
+
+// per-CPU data, never freed
+#[repr(C)]
+pub struct Cpu {
+    // read by `current` through gs:[0]
+    self_ptr: usize,
+    index: usize,
+    apic_id: u32,
+    // taken by the AP when it wakes up
+    job: spin::Mutex<Option<Job>>,
+    // from the time it is given a job until it finishes it
+    busy: AtomicBool,
+}
+
+impl Cpu {
+    // 0 for the bootstrap CPU, then in start order
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+struct Stack([u8; AP_STACK_SIZE]);
+
+// the APs can't allocate their first stack, they get it from the trampoline
+static mut AP_STACKS: [Stack; MAX_CPUS - 1] = [Stack([0; AP_STACK_SIZE]); MAX_CPUS - 1];
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+static CPUS: spin::Mutex<Vec<&'static Cpu>> = spin::Mutex::new(Vec::new());
+
+// state of the AP being started, so that the BSP and the AP agree on whether it was given up
+static AP_STATE: AtomicU8 = AtomicU8::new(AP_STARTING);
+const AP_STARTING: u8 = 0;
+// out of the trampoline, it only checks in after that
+const AP_ENTERED: u8 = 1;
+// too late, it stops in `ap_entry` if it gets there
+const AP_GIVEN_UP: u8 = 2;
+
+/* start the APs, return the number of CPUs online (at least 1, the BSP)
+
+   must be called once the heap is initialized and with interrupts enabled: the waits between the
+   IPIs use the timer ticks
+   an AP which doesn't check in within 100ms is given up and stopped with an INIT IPI (it might
+   still be running the trampoline), the APs after it aren't started
+*/
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    physical_memory_offset: VirtAddr,
+) -> Result<usize, SmpError> {
+    if INITIALIZED.load(Ordering::SeqCst) {
+        return Ok(online());
+    }
+    let madt = acpi::find_madt(physical_memory_offset)?;
+    lapic::init(mapper, frame_allocator, madt.local_apic_address)?;
+
+    // already done by the BIOS in general, needed to receive the wakeup IPIs
+    lapic::enable();
+    let bsp_id = lapic::id();
+    let bsp = set_current(0, bsp_id);
+    cpu_interrupts::without_interrupts(|| CPUS.lock().push(bsp));
+    ONLINE.store(1, Ordering::SeqCst);
+    INITIALIZED.store(true, Ordering::SeqCst);
+
+    let frame = frame_allocator.allocate_low_frame().ok_or(SmpError::NoLowMemory)?;
+    let trampoline = trampoline::Trampoline::install(mapper, frame_allocator, frame, physical_memory_offset, ap_entry)?;
+
+    let aps = madt.apic_ids.iter().filter(|&&apic_id| apic_id != bsp_id).take(MAX_CPUS - 1);
+    for (slot, &apic_id) in aps.enumerate() {
+        let index = slot + 1;
+        let stack_top = unsafe { VirtAddr::from_ptr(&AP_STACKS[slot]) } + AP_STACK_SIZE;
+        trampoline.set_cpu(stack_top.as_u64(), index);
+        AP_STATE.store(AP_STARTING, Ordering::SeqCst);
+
+        // 10ms after INIT and 200µs after each SIPI, `thread::sleep` waits at least the time given
+        lapic::send_init(apic_id);
+        thread::sleep(10);
+        lapic::send_startup(apic_id, trampoline.frame());
+        thread::sleep(1);
+        // the second SIPI is ignored if the first one started the AP
+        if online() <= index {
+            lapic::send_startup(apic_id, trampoline.frame());
+        }
+        if !wait_online(index + 1, 100) {
+            if AP_STATE.compare_exchange(AP_STARTING, AP_GIVEN_UP, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                // back to waiting for a SIPI, it doesn't run the trampoline removed below anymore
+                lapic::send_init(apic_id);
+                crate::serial_println!("warning: CPU with APIC ID {} didn't start", apic_id);
+                break;
+            }
+            // it left the trampoline just now, it checks in without waiting for anything
+            while online() <= index {
+                spin_loop_hint();
+            }
+        }
+    }
+
+    trampoline.remove(mapper);
+    Ok(online())
+}
+
+// number of CPUs which checked in
+pub fn online() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+// the CPUs online, in start order
+pub fn cpus() -> Vec<&'static Cpu> {
+    // locked with interrupts disabled, `wake_cpu` can be called from an interrupt handler
+    cpu_interrupts::without_interrupts(|| CPUS.lock().clone())
+}
+
+fn cpu(index: usize) -> Option<&'static Cpu> {
+    cpu_interrupts::without_interrupts(|| CPUS.lock().get(index).copied())
+}
+
+// data of the CPU running the caller
+pub fn current() -> &'static Cpu {
+    assert!(INITIALIZED.load(Ordering::SeqCst), "smp::init wasn't called");
+    let cpu: usize;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) cpu);
+        &*(cpu as *const Cpu)
+    }
+}
+
+// index of the current CPU, 0 before `init`
+pub fn cpu_index() -> usize {
+    if INITIALIZED.load(Ordering::SeqCst) {
+        current().index()
+    } else {
+        0
+    }
+}
+
+// bounds of the stack the current CPU runs on: the stack of the current thread on the BSP, its
+// own stack on an AP
+pub(crate) fn current_stack() -> Range<usize> {
+    match cpu_index() {
+        0 => thread::current_stack(),
+        index => {
+            let start = unsafe { &AP_STACKS[index - 1] } as *const Stack as usize;
+            start..start + AP_STACK_SIZE
+        }
+    }
+}
+
+// give `job` to the AP `index`, it runs it then halts again
+// the job is given back if the AP isn't online or is busy with another job
+pub fn run_on(index: usize, job: Job) -> Result<(), Job> {
+    match cpu(index) {
+        Some(cpu) if index != 0 => {
+            if cpu.busy.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                return Err(job);
+            }
+            *cpu.job.lock() = Some(job);
+            wake_cpu(index);
+            Ok(())
+        }
+        _ => Err(job),
+    }
+}
+
+// get the CPU `index` out of `hlt`, does nothing if it is the current CPU
+// can be called from an interrupt handler
+pub fn wake_cpu(index: usize) {
+    if index == cpu_index() {
+        return;
+    }
+    if let Some(cpu) = cpu(index) {
+        lapic::send_fixed(cpu.apic_id, interrupts::APIC_WAKEUP_VECTOR);
+    }
+}
+
+pub(crate) fn end_of_interrupt() {
+    lapic::end_of_interrupt();
+}
+
+fn wait_online(count: usize, timeout_ms: u64) -> bool {
+    let deadline = thread::ticks() + (timeout_ms * u64::from(thread::TIMER_HZ) + 999) / 1000;
+    while online() < count {
+        if thread::ticks() >= deadline {
+            return false;
+        }
+        thread::sleep(1);
+    }
+    true
+}
+
+fn set_current(index: usize, apic_id: u32) -> &'static Cpu {
+    let cpu = Box::leak(Box::new(Cpu {
+        self_ptr: 0,
+        index,
+        apic_id,
+        job: spin::Mutex::new(None),
+        busy: AtomicBool::new(false),
+    }));
+    cpu.self_ptr = cpu as *const Cpu as usize;
+    let mut gs_base = Msr::new(IA32_GS_BASE);
+    unsafe { gs_base.write(cpu.self_ptr as u64) };
+    cpu
+}
+
+// called by the trampoline, on the stack of the AP, with interrupts disabled
+extern "C" fn ap_entry(index: usize) -> ! {
+    if AP_STATE.compare_exchange(AP_STARTING, AP_ENTERED, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        // given up by the BSP, which is sending INIT
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+    gdt::init_ap();
+    interrupts::init_idt();
+    lapic::enable();
+    let cpu = set_current(index, lapic::id());
+    CPUS.lock().push(cpu);
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+
+    loop {
+        // a job given after the check would only be run after the next interrupt
+        cpu_interrupts::disable();
+        let job = cpu.job.lock().take();
+        match job {
+            Some(job) => {
+                cpu_interrupts::enable();
+                job();
+                cpu.busy.store(false, Ordering::SeqCst);
+            }
+            None => enable_interrupts_and_hlt(),
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use super::SmpError;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::{
+    registers::{control::Cr3, model_specific::Efer},
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB
+    },
+    VirtAddr,
+};
+
+/* start code of the application processors
+
+   a CPU woken by a startup IPI runs in real mode (16-bit) at the start of a page below 1 MiB: the
+   code below is copied to such a page, then goes through protected mode (32-bit) to long mode
+   (64-bit) with the page table of the bootstrap CPU, and calls the kernel entry on its own stack
+
+   - the code is position independent: %ebx holds the physical address of the page, and the
+     addresses which need it (GDT, far jumps) are patched before the copy is used
+   - the page is identity mapped, the code keeps running from it when paging is enabled
+   - cr3 is loaded in 32-bit mode, the level 4 table must be below 4 GiB
+   - the data at the end (stack, cpu) is patched again for each CPU started
+*/
+
+global_asm!("
+    .pushsection .text.smp_trampoline, \"ax\"
+    .att_syntax prefix
+    .global smp_trampoline_start
+    .code16
+    smp_trampoline_start:
+        cli
+        cld
+        mov %cs, %ax
+        mov %ax, %ds
+        xor %ebx, %ebx
+        mov %ax, %bx
+        shl $4, %ebx
+        lgdtl (smp_trampoline_gdt_pointer - smp_trampoline_start)
+        mov %cr0, %eax
+        or $1, %eax
+        mov %eax, %cr0
+        ljmpl *(smp_trampoline_protected_pointer - smp_trampoline_start)
+    .code32
+    .global smp_trampoline_protected
+    smp_trampoline_protected:
+        mov $0x10, %ax
+        mov %ax, %ds
+        mov %ax, %es
+        mov %ax, %ss
+        # PAE
+        mov %cr4, %eax
+        or $0x20, %eax
+        mov %eax, %cr4
+        mov (smp_trampoline_cr3 - smp_trampoline_start)(%ebx), %eax
+        mov %eax, %cr3
+        # EFER of the bootstrap CPU: long mode enable, no-execute enable
+        mov $0xc0000080, %ecx
+        mov (smp_trampoline_efer - smp_trampoline_start)(%ebx), %eax
+        xor %edx, %edx
+        wrmsr
+        # paging, write protect
+        mov %cr0, %eax
+        or $0x80010000, %eax
+        mov %eax, %cr0
+        ljmpl *(smp_trampoline_long_pointer - smp_trampoline_start)(%ebx)
+    .code64
+    .global smp_trampoline_long
+    smp_trampoline_long:
+        xor %eax, %eax
+        mov %ax, %ds
+        mov %ax, %es
+        mov %ax, %ss
+        mov %ebx, %ebx
+        mov (smp_trampoline_stack - smp_trampoline_start)(%rbx), %rsp
+        mov (smp_trampoline_cpu - smp_trampoline_start)(%rbx), %rdi
+        mov (smp_trampoline_entry - smp_trampoline_start)(%rbx), %rax
+        call *%rax
+        ud2
+    .balign 8
+    # null, 32-bit code, data, 64-bit code
+    .global smp_trampoline_gdt
+    smp_trampoline_gdt:
+        .quad 0
+        .quad 0x00cf9a000000ffff
+        .quad 0x00cf92000000ffff
+        .quad 0x00af9a000000ffff
+    .global smp_trampoline_gdt_pointer
+    smp_trampoline_gdt_pointer:
+        .word 31
+        .long 0
+    .balign 8
+    .global smp_trampoline_protected_pointer
+    smp_trampoline_protected_pointer:
+        .long 0
+        .word 0x08
+    .balign 8
+    .global smp_trampoline_long_pointer
+    smp_trampoline_long_pointer:
+        .long 0
+        .word 0x18
+    .balign 8
+    .global smp_trampoline_cr3
+    smp_trampoline_cr3: .quad 0
+    .global smp_trampoline_efer
+    smp_trampoline_efer: .quad 0
+    .global smp_trampoline_stack
+    smp_trampoline_stack: .quad 0
+    .global smp_trampoline_entry
+    smp_trampoline_entry: .quad 0
+    .global smp_trampoline_cpu
+    smp_trampoline_cpu: .quad 0
+    .global smp_trampoline_end
+    smp_trampoline_end:
+    .popsection
+");
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_protected: u8;
+    static smp_trampoline_long: u8;
+    static smp_trampoline_gdt: u8;
+    static smp_trampoline_gdt_pointer: u8;
+    static smp_trampoline_protected_pointer: u8;
+    static smp_trampoline_long_pointer: u8;
+    static smp_trampoline_cr3: u8;
+    static smp_trampoline_efer: u8;
+    static smp_trampoline_stack: u8;
+    static smp_trampoline_entry: u8;
+    static smp_trampoline_cpu: u8;
+    static smp_trampoline_end: u8;
+}
+
+// EFER bit set by the CPU once in long mode, the trampoline sets long mode enable instead
+const EFER_LONG_MODE_ACTIVE: u64 = 1 << 10;
+
+pub(super) type Entry = extern "C" fn(usize) -> !;
+
+pub(super) struct Trampoline {
+    frame: PhysFrame,
+    // the copy, through the physical memory mapping
+    copy: VirtAddr,
+    // false if the page was already identity mapped
+    mapped: bool,
+}
+
+// offset of a label from the start of the trampoline
+fn offset(label: &u8) -> u64 {
+    unsafe { label as *const u8 as u64 - &smp_trampoline_start as *const u8 as u64 }
+}
+
+impl Trampoline {
+    // copy the trampoline to `frame` (below 1 MiB) and identity map it
+    pub(super) fn install(
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame: PhysFrame,
+        physical_memory_offset: VirtAddr,
+        entry: Entry,
+    ) -> Result<Self, SmpError> {
+        let cr3 = Cr3::read().0.start_address().as_u64();
+        if cr3 >= 1 << 32 {
+            return Err(SmpError::PageTableAbove4GiB);
+        }
+
+        let base = frame.start_address().as_u64();
+        let page = Page::containing_address(VirtAddr::new(base));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let mapped = match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(MapToError::PageAlreadyMapped(mapped_frame)) if mapped_frame == frame => false,
+            Err(error) => return Err(error.into()),
+        };
+
+        let trampoline = Trampoline { frame, copy: physical_memory_offset + base, mapped };
+        unsafe {
+            let length = offset(&smp_trampoline_end) as usize;
+            assert!(length <= 4096, "the SMP trampoline doesn't fit in a page");
+            ptr::copy_nonoverlapping(&smp_trampoline_start as *const u8, trampoline.copy.as_mut_ptr(), length);
+
+            // the base of the GDT pointer comes after its 16-bit limit
+            trampoline.patch(&smp_trampoline_gdt_pointer, 2, (base + offset(&smp_trampoline_gdt)) as u32);
+            trampoline.patch(&smp_trampoline_protected_pointer, 0, (base + offset(&smp_trampoline_protected)) as u32);
+            trampoline.patch(&smp_trampoline_long_pointer, 0, (base + offset(&smp_trampoline_long)) as u32);
+            trampoline.patch(&smp_trampoline_cr3, 0, cr3);
+            trampoline.patch(&smp_trampoline_efer, 0, Efer::read_raw() & !EFER_LONG_MODE_ACTIVE);
+            trampoline.patch(&smp_trampoline_entry, 0, entry as usize as u64);
+        }
+        fence(Ordering::SeqCst);
+        Ok(trampoline)
+    }
+
+    pub(super) fn frame(&self) -> PhysFrame {
+        self.frame
+    }
+
+    // arguments of the next CPU started: `stack_top` must be 16 bytes aligned
+    pub(super) fn set_cpu(&self, stack_top: u64, cpu: usize) {
+        unsafe {
+            self.patch(&smp_trampoline_stack, 0, stack_top);
+            self.patch(&smp_trampoline_cpu, 0, cpu as u64);
+        }
+        // before the startup IPI
+        fence(Ordering::SeqCst);
+    }
+
+    // the GDT pointer isn't aligned
+    unsafe fn patch<T>(&self, label: &u8, extra: u64, value: T) {
+        let dest = self.copy + offset(label) + extra;
+        ptr::write_unaligned(dest.as_mut_ptr::<T>(), value);
+    }
+
+    pub(super) fn remove(self, mapper: &mut impl Mapper<Size4KiB>) {
+        if self.mapped {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(self.frame.start_address().as_u64()));
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.flush();
+            }
+        }
+    }
+}
@@ -1,106 +1,611 @@
-use super::{Task, TaskId};
-use core::task::{Waker, Context, Poll};
-use crossbeam_queue::ArrayQueue;
+use super::{RawTask, SendTask, Task, TaskId, join::JoinHandle};
+use super::scheduler::{Priority, PriorityAging, SchedulingPolicy};
+use super::stats::{self, TaskInfo, TaskState, TaskStats, DEFAULT_SLOW_POLL_CYCLES};
+use crate::serial_println;
+use crate::smp::{self, MAX_CPUS};
+use core::{
+    future::Future,
+    ptr,
+    task::{Waker, Context, Poll},
+    sync::atomic::{spin_loop_hint, AtomicBool, AtomicPtr, AtomicU64, Ordering},
+};
 use alloc::{
-    collections::BTreeMap, 
-    sync::Arc,
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
     task::Wake,
+    vec::Vec,
 };
+use x86_64::instructions::interrupts;
+
+mod multicore;
+
+pub use multicore::SendSpawner;
+use multicore::Shared;
+
+/* the tasks of an executor
+
+   - the Tasks (not Send) are local: they are only polled by the CPU running the executor
+   - the SendTasks can be polled by every CPU online, see `multicore`: `run` gives a worker to each
+     AP, `block_on` and `run_until_idle` poll them on the current CPU only
+   - both kinds are scheduled by the same policy, the tasks of each priority are polled in the
+     order they were woken
+*/
 
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    tasks: BTreeMap<TaskId, RawTask>,
+    // one ready queue per priority, indexed by `Priority::index`
+    task_queues: [Arc<ReadyQueue>; Priority::COUNT],
+    // also holds the waker of each task
+    task_list: TaskList,
+    spawner: Spawner,
+    // the SendTasks, the policy and the shutdown signal
+    shared: Arc<Shared>,
+    // woken by the future given to `block_on`
+    block_on_waker: Arc<BlockOnWaker>,
+    // in TSC cycles, None to disable the warnings
+    slow_poll_threshold: Option<u64>,
 }
 
 impl Executor {
+    // higher priority tasks first, with aging
     pub fn new() -> Self {
+        Executor::with_policy(PriorityAging::default())
+    }
+
+    // the policy is shared by every CPU running the executor
+    pub fn with_policy(policy: impl SchedulingPolicy + Send + 'static) -> Self {
+        let shared = Arc::new(Shared::new(Box::new(policy)));
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
+            task_queues: [
+                Arc::new(ReadyQueue::new(Arc::downgrade(&shared))),
+                Arc::new(ReadyQueue::new(Arc::downgrade(&shared))),
+                Arc::new(ReadyQueue::new(Arc::downgrade(&shared))),
+            ],
+            task_list: TaskList::new(),
+            spawner: Spawner::new(),
+            block_on_waker: Arc::new(BlockOnWaker {
+                woken: AtomicBool::new(false),
+                shared: Arc::downgrade(&shared),
+            }),
+            shared,
+            slow_poll_threshold: Some(DEFAULT_SLOW_POLL_CYCLES),
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("task with the same ID is already in tasks");
+    // CPUs which can run the SendTasks, those online when the executor was created
+    pub fn workers(&self) -> usize {
+        self.shared.workers()
+    }
+
+    // polls taking more than `cycles` are reported on serial, they usually come from a task
+    // blocking instead of awaiting
+    pub fn set_slow_poll_threshold(&mut self, cycles: Option<u64>) {
+        self.slow_poll_threshold = cycles;
+    }
+
+    // the local tasks not finished yet, in the order they were created
+    pub fn task_list(&self) -> Vec<TaskInfo> {
+        self.task_list.get()
+    }
+
+    // handle to list the local tasks while the executor runs, e.g. from one of its tasks
+    pub fn task_list_handle(&self) -> TaskList {
+        self.task_list.clone()
+    }
+
+    pub fn spawn<T: 'static>(&mut self, task: Task<T>) -> JoinHandle<T> {
+        let (task, join_handle) = task.into_raw();
+        self.add_task(task);
+        join_handle
+    }
+
+    pub fn spawn_send<T: Send + 'static>(&self, task: SendTask<T>) -> JoinHandle<T> {
+        self.send_spawner().spawn(task)
+    }
+
+    // handle to spawn local tasks on this executor while it runs, from the CPU running it
+    pub fn spawner(&self) -> Spawner {
+        self.spawner.clone()
+    }
+
+    // handle to spawn SendTasks on this executor, from any CPU
+    pub fn send_spawner(&self) -> SendSpawner {
+        SendSpawner {
+            shared: self.shared.clone(),
         }
+    }
 
-        self.task_queue.push(task_id).expect("queue full");
+    // signal making `run` return, e.g. from a task
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            shared: self.shared.clone(),
+        }
     }
 
-    pub fn run(&mut self) -> ! {
+    /* run the tasks until the shutdown signal is triggered
+
+       the APs run the SendTasks with their worker, given with `smp::run_on` (an AP already busy
+       with another job doesn't take part)
+       returns once every worker has stopped
+    */
+    pub fn run(&mut self) {
+        // `task::spawn` spawns on this executor while it runs
+        let previous_spawner = swap_current_spawner(&self.spawner);
+        let home = self.shared.home;
+        for index in (0..self.shared.workers()).filter(|&index| index != home) {
+            let shared = self.shared.clone();
+            // counted before it starts, so that a shutdown can't be missed
+            self.shared.running.fetch_add(1, Ordering::SeqCst);
+            if smp::run_on(index, Box::new(move || shared.run_worker(index))).is_err() {
+                self.shared.running.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        while !self.shared.shutdown.load(Ordering::Acquire) {
+            self.spawn_new_tasks();
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+        // the workers stop after the task they are polling
+        while self.shared.running.load(Ordering::SeqCst) > 0 {
+            spin_loop_hint();
+        }
+        self.shared.shutdown.store(false, Ordering::Release);
+        swap_current_spawner(previous_spawner);
+    }
+
+    // run the tasks until none of them is ready, the others stay in the executor
+    pub fn run_until_idle(&mut self) {
+        let previous_spawner = swap_current_spawner(&self.spawner);
         loop {
+            self.spawn_new_tasks();
+            if self.queues_empty() && self.shared.is_empty() {
+                break;
+            }
+            self.run_ready_tasks();
+        }
+        swap_current_spawner(previous_spawner);
+    }
+
+    // run the tasks until `future` completes and return its output
+    pub fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        futures_util::pin_mut!(future);
+        let previous_spawner = swap_current_spawner(&self.spawner);
+        let waker = Waker::from(self.block_on_waker.clone());
+        let mut context = Context::from_waker(&waker);
+
+        // polled a first time before it is woken
+        self.block_on_waker.woken.store(true, Ordering::Release);
+        let output = loop {
+            if self.block_on_waker.woken.swap(false, Ordering::AcqRel) {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                    break output;
+                }
+            }
+            self.spawn_new_tasks();
             self.run_ready_tasks();
             self.sleep_if_idle();
+        };
+        swap_current_spawner(previous_spawner);
+        output
+    }
+
+    fn add_task(&mut self, task: RawTask) {
+        let task_id = task.id;
+        let (name, priority) = (task.name, task.priority);
+        let task_queue = self.task_queues[priority.index()].clone();
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with the same ID is already in tasks");
+        }
+
+        // the waker is created right away, it is also the node of the task in the ready queue of its priority
+        let waker = TaskWaker::new(task_id, task_queue.clone());
+        self.task_list.entries.lock().insert(task_id, TaskEntry {
+            name,
+            priority,
+            stats: TaskStats::default(),
+            waker: waker.clone(),
+        });
+        task_queue.push(waker);
+    }
+
+    fn queues_empty(&self) -> bool {
+        self.task_queues.iter().all(|queue| queue.is_empty())
+    }
+
+    // add the tasks given to the spawner
+    fn spawn_new_tasks(&mut self) {
+        while let Some(task) = self.spawner.pop() {
+            self.add_task(task);
         }
     }
 
     fn run_ready_tasks(&mut self) {
+        let home = self.shared.home;
+        loop {
+            // the oldest ready task of each priority, local or Send
+            let send_tickets = self.shared.oldest_tickets(home);
+            let mut oldest_ticket = [None; Priority::COUNT];
+            for (index, oldest) in oldest_ticket.iter_mut().enumerate() {
+                *oldest = match (self.task_queues[index].oldest_ticket(), send_tickets[index]) {
+                    (Some(local), Some(send)) => Some(local.min(send)),
+                    (local, send) => local.or(send),
+                };
+            }
+            if oldest_ticket.iter().all(Option::is_none) {
+                // the SendTasks queued for the other CPUs, which might not be running their worker
+                if self.shared.steal(home) {
+                    continue;
+                }
+                break;
+            }
+
+            let priority = self.shared.pick(&oldest_ticket);
+            let polled = if oldest_ticket[priority.index()] == send_tickets[priority.index()] {
+                // None if it was stolen in between
+                match self.shared.pop(home, priority) {
+                    Some(task) => {
+                        self.shared.poll(task, home);
+                        true
+                    }
+                    None => false,
+                }
+            } else {
+                self.poll_local(priority)
+            };
+            if polled {
+                self.shared.polled(priority);
+            }
+        }
+    }
+
+    // poll the next local task of `priority`, return false if it no longer exists
+    fn poll_local(&mut self, priority: Priority) -> bool {
         // destruct self to avoid borrow checker errors
         let Self {
             tasks,
-            task_queue,
-            waker_cache,
+            task_queues,
+            task_list,
+            slow_poll_threshold,
+            ..
         } = self;
 
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                // task no longer exists
-                None => continue,
-            };
+        let task_waker = task_queues[priority.index()].pop().expect("the level has a ready task");
+        let task_id = task_waker.task_id;
+        let task = match tasks.get_mut(&task_id) {
+            Some(task) => task,
+            // task no longer exists
+            None => return false,
+        };
 
-            // get a waker from the cache or create it if it doesn't exists
-            // we clone the task_queue because it's wrapped into an Arc, so we increase the reference count but it still points to the same heap value
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                // Task return Poll::Ready if it's finished
-                Poll::Ready(()) => {
-                    // task done, remove it and cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+        // converting the Arc to a Waker doesn't allocate
+        let waker = Waker::from(task_waker);
+        let mut context = Context::from_waker(&waker);
+        let start = stats::timestamp();
+        let poll = task.poll(&mut context);
+        let cycles = stats::timestamp().wrapping_sub(start);
+        let slow = slow_poll_threshold.map_or(false, |threshold| cycles > threshold);
+        if let Some(entry) = task_list.entries.lock().get_mut(&task_id) {
+            entry.stats.record_poll(cycles);
+            if slow {
+                entry.stats.slow_polls += 1;
             }
         }
+        if slow {
+            serial_println!(
+                "warning: task {} ({}) was polled for {} cycles, is it blocking?",
+                task_id.as_u64(),
+                task.name.unwrap_or("unnamed"),
+                cycles
+            );
+        }
+
+        match poll {
+            // Task return Poll::Ready if it's finished
+            Poll::Ready(()) => {
+                // task done, remove it and its entry with the waker
+                tasks.remove(&task_id);
+                task_list.entries.lock().remove(&task_id);
+            }
+            Poll::Pending => {}
+        }
+        true
     }
 
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_interrupts_and_hlt};
-        
+        use x86_64::instructions::interrupts::enable_interrupts_and_hlt;
+
         // interrupt can happen here so we disable interrupts
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        // set before checking the queues: a task woken by another CPU after the check sends an IPI
+        self.shared.set_sleeping(self.shared.home, true);
+        let idle = self.queues_empty()
+            && self.shared.is_empty()
+            && self.spawner.is_empty()
+            && !self.block_on_waker.woken.load(Ordering::Acquire)
+            && !self.shared.shutdown.load(Ordering::Acquire);
+        if idle {
             enable_interrupts_and_hlt();
         } else {
             interrupts::enable();
         }
+        self.shared.set_sleeping(self.shared.home, false);
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        // the queued wakers reference the queue, release them to break the cycle
+        for queue in self.task_queues.iter() {
+            while queue.pop().is_some() {}
+        }
+        // the handles left list no task
+        self.task_list.entries.lock().clear();
+    }
+}
+
+// cloneable handle to spawn local tasks on an executor, from the code running on its CPU (it isn't
+// Send, like the tasks, use a SendSpawner from the other CPUs)
+// the tasks are queued and added by the executor at the start of its next loop
+// spawning allocates (the queue grows), so it must not be done from an interrupt handler
+#[derive(Clone)]
+pub struct Spawner {
+    new_tasks: Arc<spin::Mutex<VecDeque<RawTask>>>,
+}
+
+impl Spawner {
+    fn new() -> Self {
+        Spawner {
+            new_tasks: Arc::new(spin::Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn spawn<T: 'static>(&self, task: Task<T>) -> JoinHandle<T> {
+        let (task, join_handle) = task.into_raw();
+        self.new_tasks.lock().push_back(task);
+        join_handle
+    }
+
+    fn pop(&self) -> Option<RawTask> {
+        self.new_tasks.lock().pop_front()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.new_tasks.lock().is_empty()
+    }
+}
+
+// cloneable handle to list the local tasks of an executor, usable while it runs
+// the executor updates it when a task is added, after each poll and when a task completes
+#[derive(Clone)]
+pub struct TaskList {
+    entries: Arc<spin::Mutex<BTreeMap<TaskId, TaskEntry>>>,
+}
+
+struct TaskEntry {
+    name: Option<&'static str>,
+    priority: Priority,
+    // the wakes are counted by the TaskWaker
+    stats: TaskStats,
+    waker: Arc<TaskWaker>,
+}
+
+impl TaskList {
+    fn new() -> Self {
+        TaskList {
+            entries: Arc::new(spin::Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    // the local tasks not finished yet, in the order they were created
+    pub fn get(&self) -> Vec<TaskInfo> {
+        self.entries
+            .lock()
+            .iter()
+            .map(|(&id, entry)| {
+                let state = if entry.waker.queued.load(Ordering::Acquire) {
+                    TaskState::Ready
+                } else {
+                    TaskState::Waiting
+                };
+                let mut stats = entry.stats;
+                stats.wakes = entry.waker.wakes.load(Ordering::Relaxed);
+                TaskInfo {
+                    id,
+                    name: entry.name,
+                    priority: entry.priority,
+                    state,
+                    stats,
+                }
+            })
+            .collect()
+    }
+}
+
+// spawner of the executor running on each CPU, used by `task::spawn`, null if none is running
+// each thread has its own, they are swapped by the thread scheduler
+static CURRENT_SPAWNER: [AtomicPtr<Spawner>; MAX_CPUS] = [AtomicPtr::new(ptr::null_mut()); MAX_CPUS];
+
+// return the previous spawner, restored when the executor stops running (executors can be nested with `block_on`)
+// also called by the thread scheduler
+pub(crate) fn swap_current_spawner(spawner: *const Spawner) -> *const Spawner {
+    CURRENT_SPAWNER[smp::cpu_index()].swap(spawner as *mut Spawner, Ordering::AcqRel)
+}
+
+pub fn current_spawner() -> Option<Spawner> {
+    let spawner = CURRENT_SPAWNER[smp::cpu_index()].load(Ordering::Acquire);
+    // the executor is running, so it isn't moved or dropped until it restores the previous one
+    unsafe { spawner.as_ref() }.cloned()
+}
+
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    shared: Arc<Shared>,
+}
+
+impl ShutdownSignal {
+    // `Executor::run` returns after the tasks it is currently running, on every CPU
+    pub fn shutdown(&self) {
+        self.shared.trigger_shutdown();
+    }
+}
+
+struct BlockOnWaker {
+    woken: AtomicBool,
+    // to wake the CPU running `block_on`
+    shared: Weak<Shared>,
+}
+
+impl Wake for BlockOnWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        if let Some(shared) = self.shared.upgrade() {
+            shared.wake_worker(shared.home);
+        }
+    }
+}
+
+/* queue of the tasks ready to be polled
+
+   - it is an intrusive linked list whose nodes are the TaskWakers, so pushing never allocates
+     and is safe in an interrupt handler (a lock-free push onto a stack)
+   - a task is queued at most once: waking a task which is already queued does nothing, so the
+     queue never holds more entries than there are tasks
+   - the executor takes the whole stack at once and reverses it to poll in FIFO order
+   - each wakeup gets a ticket from WAKE_TICKETS, used by the scheduling policies to compare
+     how long the queues have been waiting (the SendTasks get theirs from it too)
+   - a task woken by another CPU wakes the CPU running the executor with an IPI if it is halted
+*/
+
+static WAKE_TICKETS: AtomicU64 = AtomicU64::new(0);
+
+struct ReadyQueue {
+    // stack of the tasks woken since the executor last took them, latest first
+    pushed: AtomicPtr<TaskWaker>,
+    // tasks taken from `pushed`, in the order they were woken, only used by the executor
+    ready: spin::Mutex<*const TaskWaker>,
+    // to wake the CPU running the executor
+    shared: Weak<Shared>,
+}
+
+// the raw pointers are Arc<TaskWaker> owned by the queue
+unsafe impl Send for ReadyQueue {}
+unsafe impl Sync for ReadyQueue {}
+
+impl ReadyQueue {
+    fn new(shared: Weak<Shared>) -> Self {
+        ReadyQueue {
+            pushed: AtomicPtr::new(ptr::null_mut()),
+            ready: spin::Mutex::new(ptr::null()),
+            shared,
+        }
+    }
+
+    fn push(&self, waker: Arc<TaskWaker>) {
+        // already queued, it will be polled anyway
+        if waker.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        waker.ticket.store(WAKE_TICKETS.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+        let node = Arc::into_raw(waker) as *mut TaskWaker;
+        let mut head = self.pushed.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*node).next.store(head, Ordering::Relaxed) };
+            match self.pushed.compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+
+        if let Some(shared) = self.shared.upgrade() {
+            shared.wake_worker(shared.home);
+        }
+    }
+
+    // move the pushed tasks to `ready` if it is empty
+    fn take_pushed(&self, ready: &mut *const TaskWaker) {
+        if ready.is_null() {
+            // take every pushed task and reverse the list, so that the first woken is polled first
+            let mut node = self.pushed.swap(ptr::null_mut(), Ordering::Acquire);
+            let mut reversed = ptr::null_mut();
+            while !node.is_null() {
+                let next = unsafe { (*node).next.load(Ordering::Relaxed) };
+                unsafe { (*node).next.store(reversed, Ordering::Relaxed) };
+                reversed = node;
+                node = next;
+            }
+            *ready = reversed;
+        }
+    }
+
+    // ticket of the task that will be popped next
+    fn oldest_ticket(&self) -> Option<u64> {
+        let mut ready = self.ready.lock();
+        self.take_pushed(&mut ready);
+        if ready.is_null() {
+            None
+        } else {
+            Some(unsafe { (**ready).ticket.load(Ordering::Relaxed) })
+        }
+    }
+
+    // only called by the executor
+    fn pop(&self) -> Option<Arc<TaskWaker>> {
+        let mut ready = self.ready.lock();
+        self.take_pushed(&mut ready);
+        if ready.is_null() {
+            return None;
+        }
+
+        let waker = unsafe { Arc::from_raw(*ready) };
+        *ready = waker.next.load(Ordering::Relaxed);
+        // a wake from now on (e.g. during the poll) queues the task again
+        waker.queued.store(false, Ordering::Release);
+        Some(waker)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ready.lock().is_null() && self.pushed.load(Ordering::Acquire).is_null()
     }
 }
 
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: Arc<ReadyQueue>,
+    // set while the waker is in `task_queue`
+    queued: AtomicBool,
+    // next node in `task_queue`
+    next: AtomicPtr<TaskWaker>,
+    // WAKE_TICKETS value when the waker was queued
+    ticket: AtomicU64,
+    // calls to wake, including those of an already queued task
+    wakes: AtomicU64,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        // create a TaskWaker, wrap it inside an Arc and convert it to a Waker
-        Waker::from(Arc::new(TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ReadyQueue>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
             task_id,
             task_queue,
-        }))
+            queued: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+            ticket: AtomicU64::new(0),
+            wakes: AtomicU64::new(0),
+        })
     }
 
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue is full");
+    fn wake_task(self: Arc<Self>) {
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+        // clone to get the queue, the waker itself is moved into it
+        let task_queue = self.task_queue.clone();
+        task_queue.push(self);
     }
 }
 
@@ -110,6 +615,6 @@ impl Wake for TaskWaker {
     }
 
     fn wake_by_ref(self: &Arc<Self>) {
-        self.wake_task();
+        self.clone().wake_task();
     }
-}
\ No newline at end of file
+}
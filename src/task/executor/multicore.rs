@@ -0,0 +1,360 @@
+use super::WAKE_TICKETS;
+use crate::smp;
+use crate::task::{join::JoinHandle, local::SendTaskLocals, scheduler::{Priority, SchedulingPolicy}, SendTask};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Waker},
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering},
+};
+use alloc::{
+    boxed::Box,
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    task::Wake,
+    vec::Vec,
+};
+use x86_64::instructions::interrupts;
+
+/* the SendTasks of an Executor, run by every CPU online
+
+   - each CPU has a worker with one ready queue per priority, a new task goes to the queue of the
+     CPU spawning it (or the CPU of the executor)
+   - a task woken goes back to the queue of the worker which polled it last, its data is likely
+     still in the cache of that CPU
+   - a worker without ready task steals half of a queue of another one (highest priority first)
+     before halting
+   - a halted worker is woken by an IPI when a task is queued for it, or when a task is queued for
+     a busy worker (to steal it)
+   - the workers share the scheduling policy of the executor
+   - the tasks aren't stored by the executor: a pending task is owned by its wakers, and dropped if
+     none of them is left
+*/
+
+pub(super) struct Shared {
+    workers: Vec<Worker>,
+    // index of the CPU running the executor, whose worker also runs the local tasks
+    pub(super) home: usize,
+    pub(super) shutdown: AtomicBool,
+    // AP workers started by `Executor::run` and not stopped yet
+    pub(super) running: AtomicUsize,
+    // locked with interrupts disabled, like the queues
+    policy: spin::Mutex<Box<dyn SchedulingPolicy + Send>>,
+}
+
+struct Worker {
+    // locked with interrupts disabled, tasks can be woken by interrupt handlers
+    queues: [spin::Mutex<VecDeque<Arc<SharedTask>>>; Priority::COUNT],
+    // set while the worker may be halted, cleared by whoever wakes it
+    sleeping: AtomicBool,
+}
+
+impl Worker {
+    fn new() -> Self {
+        Worker {
+            queues: [
+                spin::Mutex::new(VecDeque::new()),
+                spin::Mutex::new(VecDeque::new()),
+                spin::Mutex::new(VecDeque::new()),
+            ],
+            sleeping: AtomicBool::new(false),
+        }
+    }
+
+    fn push(&self, task: Arc<SharedTask>) {
+        task.ticket.store(WAKE_TICKETS.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+        let queue = &self.queues[task.priority.index()];
+        interrupts::without_interrupts(|| queue.lock().push_back(task));
+    }
+
+    fn pop(&self, priority: Priority) -> Option<Arc<SharedTask>> {
+        interrupts::without_interrupts(|| self.queues[priority.index()].lock().pop_front())
+    }
+
+    fn oldest_ticket(&self, priority: Priority) -> Option<u64> {
+        interrupts::without_interrupts(|| {
+            self.queues[priority.index()].lock().front().map(|task| task.ticket.load(Ordering::Relaxed))
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        interrupts::without_interrupts(|| self.queues.iter().all(|queue| queue.lock().is_empty()))
+    }
+
+    // the second half of a queue, the tasks queued last
+    fn steal_half(&self, priority: Priority) -> Vec<Arc<SharedTask>> {
+        interrupts::without_interrupts(|| {
+            let mut queue = self.queues[priority.index()].lock();
+            let keep = queue.len() / 2;
+            queue.split_off(keep).into_iter().collect()
+        })
+    }
+}
+
+impl Shared {
+    // one worker per CPU online, smp::init must be called first to use the APs
+    pub(super) fn new(policy: Box<dyn SchedulingPolicy + Send>) -> Self {
+        let workers: Vec<Worker> = (0..smp::online().max(1)).map(|_| Worker::new()).collect();
+        let home = smp::cpu_index();
+        Shared {
+            home: if home < workers.len() { home } else { 0 },
+            workers,
+            shutdown: AtomicBool::new(false),
+            running: AtomicUsize::new(0),
+            policy: spin::Mutex::new(policy),
+        }
+    }
+
+    pub(super) fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    // queue a task on its home worker
+    fn schedule(&self, task: Arc<SharedTask>) {
+        let mut state = task.state.load(Ordering::Acquire);
+        loop {
+            let new_state = match state {
+                IDLE => QUEUED,
+                // polled again by the worker running it once the poll returns
+                RUNNING => NOTIFIED,
+                // already queued or finished
+                _ => return,
+            };
+            match task.state.compare_exchange_weak(state, new_state, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) if new_state == QUEUED => break,
+                Ok(_) => return,
+                Err(current) => state = current,
+            }
+        }
+        let home = task.home.load(Ordering::Relaxed);
+        self.workers[home].push(task);
+        self.notify(home);
+    }
+
+    // wake the worker `home` if it is halted, or else a halted worker which can steal the task
+    fn notify(&self, home: usize) {
+        let sleeping = |index: &usize| self.workers[*index].sleeping.load(Ordering::SeqCst);
+        let target = Some(home)
+            .filter(sleeping)
+            .or_else(|| (0..self.workers.len()).find(sleeping));
+        if let Some(index) = target {
+            self.wake_worker(index);
+        }
+    }
+
+    pub(super) fn wake_worker(&self, index: usize) {
+        if self.workers[index].sleeping.swap(false, Ordering::SeqCst) {
+            smp::wake_cpu(index);
+        }
+    }
+
+    // the halted workers only see the shutdown once woken
+    pub(super) fn trigger_shutdown(&self) {
+        self.shutdown.store(true, Ordering::Release);
+        for index in 0..self.workers.len() {
+            self.wake_worker(index);
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.workers.iter().all(Worker::is_empty)
+    }
+
+    // ticket of the oldest task of each priority in the queues of the worker `index`
+    pub(super) fn oldest_tickets(&self, index: usize) -> [Option<u64>; Priority::COUNT] {
+        let worker = &self.workers[index];
+        let mut oldest_ticket = [None; Priority::COUNT];
+        for &priority in Priority::ALL.iter() {
+            oldest_ticket[priority.index()] = worker.oldest_ticket(priority);
+        }
+        oldest_ticket
+    }
+
+    // let the policy choose between the priorities which have ready tasks, at least one has some
+    pub(super) fn pick(&self, oldest_ticket: &[Option<u64>; Priority::COUNT]) -> Priority {
+        let ticket = WAKE_TICKETS.load(Ordering::Relaxed);
+        let mut priority = interrupts::without_interrupts(|| self.policy.lock().pick(oldest_ticket, ticket));
+        // a policy picking a level without ready task gets the highest level that has some
+        if oldest_ticket[priority.index()].is_none() {
+            priority = *Priority::ALL.iter().rev()
+                .find(|priority| oldest_ticket[priority.index()].is_some())
+                .unwrap();
+        }
+        priority
+    }
+
+    pub(super) fn polled(&self, priority: Priority) {
+        interrupts::without_interrupts(|| self.policy.lock().polled(priority));
+    }
+
+    pub(super) fn pop(&self, index: usize, priority: Priority) -> Option<Arc<SharedTask>> {
+        self.workers[index].pop(priority)
+    }
+
+    // move half of a queue of the first worker which has ready tasks to the worker `index`
+    // return false if there was nothing to steal
+    pub(super) fn steal(&self, index: usize) -> bool {
+        let count = self.workers.len();
+        for victim in (1..count).map(|offset| (index + offset) % count) {
+            for &priority in Priority::ALL.iter().rev() {
+                // the victim is unlocked before locking our queue, two workers may steal from each other
+                let stolen = self.workers[victim].steal_half(priority);
+                if stolen.is_empty() {
+                    continue;
+                }
+                for task in stolen {
+                    task.home.store(index, Ordering::Relaxed);
+                    self.workers[index].push(task);
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    // loop of the AP workers, until the shutdown
+    pub(super) fn run_worker(&self, index: usize) {
+        while !self.shutdown.load(Ordering::Acquire) {
+            match self.next_task(index) {
+                Some((task, priority)) => {
+                    self.poll(task, index);
+                    self.polled(priority);
+                }
+                None => self.sleep_if_idle(index),
+            }
+        }
+        self.running.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // from the queues of the worker, or stolen from another worker
+    fn next_task(&self, index: usize) -> Option<(Arc<SharedTask>, Priority)> {
+        let mut oldest_ticket = self.oldest_tickets(index);
+        if oldest_ticket.iter().all(Option::is_none) {
+            if !self.steal(index) {
+                return None;
+            }
+            oldest_ticket = self.oldest_tickets(index);
+            if oldest_ticket.iter().all(Option::is_none) {
+                return None;
+            }
+        }
+        let priority = self.pick(&oldest_ticket);
+        // None if the task was stolen in between
+        self.pop(index, priority).map(|task| (task, priority))
+    }
+
+    pub(super) fn poll(&self, task: Arc<SharedTask>, index: usize) {
+        // its next wakes go to this worker
+        task.home.store(index, Ordering::Relaxed);
+        // a wake from now on (e.g. during the poll) sets NOTIFIED
+        task.state.store(RUNNING, Ordering::Release);
+
+        // never contended, only the worker which set RUNNING polls the task
+        let mut future = task.future.lock();
+        let mut locals = task.locals.lock();
+        let done = match future.as_mut() {
+            Some(future) => {
+                let waker = Waker::from(task.clone());
+                let mut context = Context::from_waker(&waker);
+                locals.enter(|| future.as_mut().poll(&mut context)).is_ready()
+            }
+            None => true,
+        };
+        if done {
+            *future = None;
+            *locals = SendTaskLocals::default();
+            task.state.store(DONE, Ordering::Release);
+            return;
+        }
+        drop(locals);
+        drop(future);
+
+        if task.state.compare_exchange(RUNNING, IDLE, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            // woken during the poll
+            task.state.store(QUEUED, Ordering::Release);
+            self.workers[index].push(task);
+        }
+    }
+
+    fn sleep_if_idle(&self, index: usize) {
+        use x86_64::instructions::interrupts::enable_interrupts_and_hlt;
+
+        let worker = &self.workers[index];
+        interrupts::disable();
+        // set before checking the queues: a task queued after the check sends an IPI
+        worker.sleeping.store(true, Ordering::SeqCst);
+        if self.is_empty() && !self.shutdown.load(Ordering::Acquire) {
+            enable_interrupts_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+        worker.sleeping.store(false, Ordering::SeqCst);
+    }
+
+    // called by the executor before checking if it is idle
+    pub(super) fn set_sleeping(&self, index: usize, sleeping: bool) {
+        self.workers[index].sleeping.store(sleeping, Ordering::SeqCst);
+    }
+}
+
+// states of a SharedTask
+const IDLE: u8 = 0;
+const QUEUED: u8 = 1;
+const RUNNING: u8 = 2;
+// woken while running
+const NOTIFIED: u8 = 3;
+const DONE: u8 = 4;
+
+pub(super) struct SharedTask {
+    priority: Priority,
+    state: AtomicU8,
+    // None once finished
+    future: spin::Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    // current while the task is polled
+    locals: spin::Mutex<SendTaskLocals>,
+    // worker whose queue the task goes to when woken
+    home: AtomicUsize,
+    // WAKE_TICKETS value when the task was queued
+    ticket: AtomicU64,
+    // weak: the queues of the executor hold the tasks
+    shared: Weak<Shared>,
+}
+
+impl Wake for SharedTask {
+    fn wake(self: Arc<Self>) {
+        // does nothing once the executor is dropped
+        if let Some(shared) = self.shared.upgrade() {
+            shared.schedule(self);
+        }
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.clone().wake();
+    }
+}
+
+// cloneable handle to spawn SendTasks on an executor, from any CPU
+#[derive(Clone)]
+pub struct SendSpawner {
+    pub(super) shared: Arc<Shared>,
+}
+
+impl SendSpawner {
+    pub fn spawn<T: Send + 'static>(&self, task: SendTask<T>) -> JoinHandle<T> {
+        let priority = task.priority;
+        let (future, locals, join_handle) = task.into_parts();
+        let home = smp::cpu_index();
+        let home = if home < self.shared.workers.len() { home } else { self.shared.home };
+        let task = Arc::new(SharedTask {
+            priority,
+            state: AtomicU8::new(IDLE),
+            future: spin::Mutex::new(Some(future)),
+            locals: spin::Mutex::new(locals),
+            home: AtomicUsize::new(home),
+            ticket: AtomicU64::new(0),
+            shared: Arc::downgrade(&self.shared),
+        });
+        self.shared.schedule(task);
+        join_handle
+    }
+}
@@ -0,0 +1,130 @@
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+/* bridge between an interrupt handler and a task
+
+   the handler pushes the data of each interrupt (scancode, packet, ...) in the queue of a static
+   IrqEvent and wakes the task reading its IrqStream:
+
+       static PACKETS: IrqEvent<Packet> = IrqEvent::new();
+
+       // in the interrupt handler
+       if let Err(error) = PACKETS.push(packet) { ... }
+
+       // in a task
+       let mut packets = PACKETS.stream(64);
+       while let Some(packet) = packets.next().await { ... }
+
+   - the queue is a lock-free ArrayQueue, allocated when the stream is created: nothing is
+     allocated or locked by the handler
+   - a full queue drops the new data, the drops are counted as overflows
+   - there is a single stream per event, it never ends
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushError {
+    // the queue is full, the data is dropped and counted as an overflow
+    Full,
+    // no stream created yet, the data is dropped
+    NoStream,
+}
+
+pub struct IrqEvent<T> {
+    // OnceCell: initialized by `stream`, never in the interrupt handler
+    queue: OnceCell<ArrayQueue<T>>,
+    waker: AtomicWaker,
+    overflows: AtomicU64,
+}
+
+impl<T> IrqEvent<T> {
+    pub const fn new() -> Self {
+        IrqEvent {
+            queue: OnceCell::uninit(),
+            waker: AtomicWaker::new(),
+            overflows: AtomicU64::new(0),
+        }
+    }
+
+    // can be called from an interrupt handler
+    pub fn push(&self, value: T) -> Result<(), PushError> {
+        let queue = self.queue.try_get().map_err(|_| PushError::NoStream)?;
+        if queue.push(value).is_err() {
+            self.overflows.fetch_add(1, Ordering::Relaxed);
+            return Err(PushError::Full);
+        }
+        // if a waker is registered, wake() notifies the executor of the task reading the stream
+        self.waker.wake();
+        Ok(())
+    }
+
+    // data dropped because the queue was full
+    pub fn overflows(&self) -> u64 {
+        self.overflows.load(Ordering::Relaxed)
+    }
+
+    // the stream of the data pushed from now on, with room for `capacity` values not read yet
+    // panics if called more than once
+    pub fn stream(&'static self, capacity: usize) -> IrqStream<T> {
+        self.queue
+            .try_init_once(|| ArrayQueue::new(capacity))
+            .expect("IrqEvent::stream should only be called once");
+        IrqStream { event: self }
+    }
+}
+
+pub struct IrqStream<T: 'static> {
+    event: &'static IrqEvent<T>,
+}
+
+impl<T> IrqStream<T> {
+    fn queue(&self) -> &ArrayQueue<T> {
+        self.event.queue.try_get().expect("created with the queue")
+    }
+
+    // the next value if there is one already, without waiting
+    pub fn try_next(&mut self) -> Option<T> {
+        self.queue().pop().ok()
+    }
+
+    // values waiting to be read
+    pub fn len(&self) -> usize {
+        self.queue().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue().is_empty()
+    }
+
+    pub fn overflows(&self) -> u64 {
+        self.event.overflows()
+    }
+}
+
+impl<T> Stream for IrqStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let queue = self.queue();
+
+        // avoid the cost of registering the waker if the queue isn't empty
+        if let Ok(value) = queue.pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        self.event.waker.register(cx.waker());
+        // a value pushed before the waker was registered didn't wake us
+        match queue.pop() {
+            Ok(value) => {
+                self.event.waker.take();
+                Poll::Ready(Some(value))
+            }
+            Err(_) => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use futures_util::task::AtomicWaker;
+
+// shared between a task and its JoinHandle
+pub(super) struct JoinState<T> {
+    // None once taken by the JoinHandle, or if the task was aborted
+    output: spin::Mutex<Option<T>>,
+    finished: AtomicBool,
+    aborted: AtomicBool,
+    // waker of the task awaiting the JoinHandle
+    join_waker: AtomicWaker,
+    // waker of the task itself, to get it polled (and dropped) when aborted
+    task_waker: AtomicWaker,
+}
+
+impl<T> JoinState<T> {
+    pub(super) fn new() -> Arc<Self> {
+        Arc::new(JoinState {
+            output: spin::Mutex::new(None),
+            finished: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+            join_waker: AtomicWaker::new(),
+            task_waker: AtomicWaker::new(),
+        })
+    }
+
+    fn finish(&self, output: Option<T>) {
+        *self.output.lock() = output;
+        self.finished.store(true, Ordering::Release);
+        self.join_waker.wake();
+    }
+}
+
+// the future run by the executor: polls the future of the task and stores its output in the JoinState
+pub(super) struct Joinable<F: Future> {
+    future: F,
+    state: Arc<JoinState<F::Output>>,
+}
+
+impl<F: Future> Joinable<F> {
+    pub(super) fn new(future: F, state: Arc<JoinState<F::Output>>) -> Self {
+        Joinable { future, state }
+    }
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // `future` is never moved out of the pinned Joinable
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.state.aborted.load(Ordering::Acquire) {
+            // the executor drops the task (and the future) when it is ready
+            this.state.finish(None);
+            return Poll::Ready(());
+        }
+
+        this.state.task_waker.register(cx.waker());
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        match future.poll(cx) {
+            Poll::Ready(output) => {
+                this.state.finish(Some(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    Aborted,
+}
+
+// returned by `Executor::spawn`, resolves to the output of the task
+// dropping the handle detaches the task, it keeps running
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new(state: Arc<JoinState<T>>) -> Self {
+        JoinHandle { state }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+
+    // the task is dropped the next time the executor gets to it, without being polled again
+    pub fn abort(&self) {
+        if !self.is_finished() {
+            self.state.aborted.store(true, Ordering::Release);
+            self.state.task_waker.wake();
+        }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, JoinError>> {
+        if !self.is_finished() {
+            self.state.join_waker.register(cx.waker());
+            // the task may have finished before the waker was registered
+            if !self.is_finished() {
+                return Poll::Pending;
+            }
+        }
+
+        match self.state.output.lock().take() {
+            Some(output) => Poll::Ready(Ok(output)),
+            None => Poll::Ready(Err(JoinError::Aborted)),
+        }
+    }
+}
@@ -1,9 +1,6 @@
-use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
 use futures_util::{
     StreamExt,
     stream::Stream,
-    task::AtomicWaker
 };
 use core::{
     pin::Pin,
@@ -14,64 +11,43 @@ use core::{
 use pc_keyboard::{Keyboard, ScancodeSet1, layouts, HandleControl, DecodedKey};
 
 use crate::{print, println};
+use super::irq::{IrqEvent, IrqStream, PushError};
 
-// use OneCell to perform safe one-time initialization of static
-// advanatge over lazy_static : initialization does not happen in the interrupt handler
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+const SCANCODE_QUEUE_SIZE: usize = 100;
 
-static WAKER: AtomicWaker = AtomicWaker::new();
+static SCANCODES: IrqEvent<u8> = IrqEvent::new();
 
 // pub(crate) so we can use it only inside lib.rs
 pub(crate) fn add_scancode(scancode: u8) {
-    // try to get a reference to the scancode queue
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full, dropping keyboard input");
-        } else {
-            // if a waker is registered in the WAKER, wake() will notify the registered waker executor
-            WAKER.wake();
-        }
-    } else {
-        println!("WARNING: scnacode queue not initialized");
+    match SCANCODES.push(scancode) {
+        Ok(()) => {}
+        Err(PushError::Full) => println!("WARNING: scancode queue full, dropping keyboard input"),
+        Err(PushError::NoStream) => println!("WARNING: scnacode queue not initialized"),
     }
 }
 
 
-// private field prevent construction from outside of the module
 pub struct ScancodeStream {
-    _private: (),
+    scancodes: IrqStream<u8>,
 }
 
 impl ScancodeStream {
     pub fn new() -> Self {
-        // panic if queue is already initialized
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+        // panics if called more than once
+        ScancodeStream { scancodes: SCANCODES.stream(SCANCODE_QUEUE_SIZE) }
+    }
+
+    // scancodes dropped because the queue was full
+    pub fn overflows(&self) -> u64 {
+        self.scancodes.overflows()
     }
 }
 
 impl Stream for ScancodeStream {
     type Item = u8;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE.try_get().expect("not initialized");
-        
-        // avoid performance oberhead of registering  a waker if the queue is not empty
-        if let Ok(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
-        }
-        
-        WAKER.register(&cx.waker());
-        match queue.pop() {
-            Ok(scancode) => {
-                // remove waker
-                WAKER.take();
-                Poll::Ready(Some(scancode))
-            }
-            // queue is empty, but with a registered waker
-            Err(crossbeam_queue::PopError) => Poll::Pending,
-        }
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        self.scancodes.poll_next_unpin(cx)
     }
 }
 
@@ -89,4 +65,4 @@ pub async fn print_keypresses() {
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,165 @@
+use crate::smp::{self, MAX_CPUS};
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::{
+    any::Any,
+    cell::RefCell,
+    marker::PhantomData,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/* task-local storage
+
+   values which follow a task across its awaits (log span, request ID, ...), declared with
+   `task_local!` and set with `Task::with_local` or `LocalKey::set`
+
+   - each task has its own map of values, the Executor makes it current for the duration of each
+     poll of the task, so that any code called by the future finds it
+   - the current map is per CPU, and saved with the context of a thread when it is preempted
+   - the values are only reachable from the task: the future given to `block_on` sees the values
+     of the task calling it
+   - the values of a SendTask move between CPUs with it, they must be Send: `LocalKey::set` only
+     takes Send values, the others can be given to a local Task with `Task::with_local`
+*/
+
+// declare task-local values: `task_local! { pub static REQUEST_ID: u64; }`
+#[macro_export]
+macro_rules! task_local {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty;)+) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::task::local::LocalKey<$t> = $crate::task::local::LocalKey::new();
+        )+
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessError {
+    // not called from a task polled by an Executor
+    NoTask,
+    // the current task has no value for this key
+    NotSet,
+}
+
+// a key to a value of type T in the storage of each task, created by `task_local!`
+pub struct LocalKey<T: 'static> {
+    // the address of the key identifies it, a zero sized static could share it with another one
+    _unique: u8,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T: 'static> LocalKey<T> {
+    // use `task_local!`
+    pub const fn new() -> Self {
+        LocalKey {
+            _unique: 0,
+            _type: PhantomData,
+        }
+    }
+
+    fn id(&'static self) -> usize {
+        self as *const Self as usize
+    }
+
+    // call `f` with the value of the current task
+    // panics outside of a task or if the value isn't set, see `try_with`
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        match self.try_with(f) {
+            Ok(output) => output,
+            Err(error) => panic!("task-local value not accessible: {:?}", error),
+        }
+    }
+
+    pub fn try_with<R>(&'static self, f: impl FnOnce(&T) -> R) -> Result<R, AccessError> {
+        with_current(|locals| {
+            let values = locals.values.borrow();
+            let value = values.get(&self.id()).ok_or(AccessError::NotSet)?;
+            // only inserted by `insert` with the type of the key
+            Ok(f(value.downcast_ref().expect("task-local value of the wrong type")))
+        })
+    }
+
+    pub fn get(&'static self) -> T where T: Clone {
+        self.with(T::clone)
+    }
+
+    pub fn is_set(&'static self) -> bool {
+        self.try_with(|_| ()).is_ok()
+    }
+
+    // set the value of the current task, return the previous one
+    // panics when called inside `with`
+    pub fn set(&'static self, value: T) -> Result<Option<T>, AccessError> where T: Send {
+        with_current(|locals| Ok(locals.insert(self, value)))
+    }
+
+    // remove the value of the current task
+    pub fn take(&'static self) -> Result<Option<T>, AccessError> {
+        with_current(|locals| {
+            let value = locals.values.borrow_mut().remove(&self.id());
+            Ok(value.map(|value| *value.downcast().expect("task-local value of the wrong type")))
+        })
+    }
+}
+
+// the values of a task
+#[derive(Default)]
+pub(crate) struct TaskLocals {
+    // by key address, borrowed while `with` runs
+    values: RefCell<BTreeMap<usize, Box<dyn Any>>>,
+}
+
+impl TaskLocals {
+    pub(super) fn insert<T: 'static>(&self, key: &'static LocalKey<T>, value: T) -> Option<T> {
+        let previous = self.values.borrow_mut().insert(key.id(), Box::new(value));
+        previous.map(|previous| *previous.downcast().expect("task-local value of the wrong type"))
+    }
+
+    // make these values current while `f` runs
+    pub(super) fn enter<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        let previous = swap_current(self as *mut TaskLocals);
+        let output = f();
+        swap_current(previous);
+        output
+    }
+}
+
+// the values of a SendTask, which can be polled by another CPU each time
+#[derive(Default)]
+pub(crate) struct SendTaskLocals(TaskLocals);
+
+// only Send values are inserted: by `insert` and by `LocalKey::set`, the values of a Task which
+// isn't Send are never in it
+unsafe impl Send for SendTaskLocals {}
+
+impl SendTaskLocals {
+    pub(super) fn insert<T: Send + 'static>(&mut self, key: &'static LocalKey<T>, value: T) -> Option<T> {
+        self.0.insert(key, value)
+    }
+
+    pub(super) fn enter<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        self.0.enter(f)
+    }
+
+    // when the SendTask is turned into a Task
+    pub(super) fn into_inner(self) -> TaskLocals {
+        self.0
+    }
+}
+
+// values of the task being polled on each CPU, null outside of a poll
+static CURRENT: [AtomicPtr<TaskLocals>; MAX_CPUS] = [AtomicPtr::new(ptr::null_mut()); MAX_CPUS];
+
+// also called by the thread scheduler, to give each thread its own current task
+pub(crate) fn swap_current(locals: *mut TaskLocals) -> *mut TaskLocals {
+    CURRENT[smp::cpu_index()].swap(locals, Ordering::AcqRel)
+}
+
+fn with_current<R>(f: impl FnOnce(&TaskLocals) -> Result<R, AccessError>) -> Result<R, AccessError> {
+    let locals = CURRENT[smp::cpu_index()].load(Ordering::Acquire);
+    if locals.is_null() {
+        return Err(AccessError::NoTask);
+    }
+    // valid until the end of the poll, which is running this code
+    f(unsafe { &*locals })
+}
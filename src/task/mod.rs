@@ -4,35 +4,188 @@ use core::{
     task::{Context, Poll},
     sync::atomic::{AtomicU64,Ordering}
 };
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc};
 
 pub mod simple_executor;
 pub mod executor;
 pub mod keyboard;
+pub mod irq;
+pub mod join;
+pub mod local;
+pub mod scheduler;
+pub mod stats;
+pub mod sync;
 
-pub struct Task {
+use join::{JoinHandle, JoinState, Joinable};
+use local::{LocalKey, SendTaskLocals, TaskLocals};
+use scheduler::Priority;
+
+// spawn a task on the executor running on the current CPU, can be called from its tasks (not from
+// a SendTask polled by another CPU, see `Executor::send_spawner`)
+// it allocates, so it must not be called from an interrupt handler
+pub fn spawn<T: 'static>(task: Task<T>) -> JoinHandle<T> {
+    executor::current_spawner()
+        .expect("task::spawn called while no executor is running")
+        .spawn(task)
+}
+
+// run `future` to completion on a new executor, with the tasks it spawns
+// the tasks still pending when it completes are dropped
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    executor::Executor::new().block_on(future)
+}
+
+// let the other ready tasks run: returns Pending once, waking itself
+pub fn yield_now() -> YieldNow {
+    YieldNow(false)
+}
+
+pub struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// a future with output T, spawned with `Executor::spawn` which returns a JoinHandle<T>
+pub struct Task<T = ()> {
+    raw: RawTask,
+    join_state: Arc<JoinState<T>>,
+}
+
+impl<T: 'static> Task<T> {
+    pub fn new(future: impl Future<Output = T> + 'static) -> Task<T> {
+        // the output is stored in the JoinState, the executor only sees a Future<Output = ()>
+        let join_state = JoinState::new();
+        Task {
+            raw: RawTask::new(Joinable::new(future, join_state.clone())),
+            join_state,
+        }
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Task<T> {
+        self.raw.priority = priority;
+        self
+    }
+
+    // shown in `Executor::task_list` and the slow poll warnings
+    pub fn with_name(mut self, name: &'static str) -> Task<T> {
+        self.raw.name = Some(name);
+        self
+    }
+
+    // value of `key` for this task, see `task_local!`
+    pub fn with_local<V: 'static>(self, key: &'static LocalKey<V>, value: V) -> Task<T> {
+        self.raw.locals.insert(key, value);
+        self
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.raw.poll(context)
+    }
+
+    // the task without its output type, as stored by the executor, and the handle to its output
+    fn into_raw(self) -> (RawTask, JoinHandle<T>) {
+        (self.raw, JoinHandle::new(self.join_state))
+    }
+}
+
+// a task whose future and output can move between CPUs, spawned with `Executor::spawn_send`
+// it can also be run as a local Task (see the From impl)
+pub struct SendTask<T = ()> {
+    name: Option<&'static str>,
+    priority: Priority,
+    locals: SendTaskLocals,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    join_state: Arc<JoinState<T>>,
+}
+
+impl<T: Send + 'static> SendTask<T> {
+    pub fn new(future: impl Future<Output = T> + Send + 'static) -> SendTask<T> {
+        let join_state = JoinState::new();
+        SendTask {
+            name: None,
+            priority: Priority::default(),
+            locals: SendTaskLocals::default(),
+            future: Box::pin(Joinable::new(future, join_state.clone())),
+            join_state,
+        }
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> SendTask<T> {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_name(mut self, name: &'static str) -> SendTask<T> {
+        self.name = Some(name);
+        self
+    }
+
+    // value of `key` for this task, it follows the task from CPU to CPU
+    pub fn with_local<V: Send + 'static>(mut self, key: &'static LocalKey<V>, value: V) -> SendTask<T> {
+        self.locals.insert(key, value);
+        self
+    }
+
+    // the future run by the executor with its task-locals, and the handle to its output
+    fn into_parts(self) -> (Pin<Box<dyn Future<Output = ()> + Send>>, SendTaskLocals, JoinHandle<T>) {
+        (self.future, self.locals, JoinHandle::new(self.join_state))
+    }
+}
+
+impl<T: 'static> From<SendTask<T>> for Task<T> {
+    fn from(task: SendTask<T>) -> Task<T> {
+        let mut raw = RawTask::new(task.future);
+        raw.name = task.name;
+        raw.priority = task.priority;
+        raw.locals = task.locals.into_inner();
+        Task {
+            raw,
+            join_state: task.join_state,
+        }
+    }
+}
+
+struct RawTask {
     id: TaskId,
+    name: Option<&'static str>,
+    priority: Priority,
+    // current while the task is polled
+    locals: TaskLocals,
     // dyn : dynamically dispatched, indicates that we store a trait object in the Box
     // Pin : prevent the value from being moved in memory (because futures might be self referential)
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
-impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+impl RawTask {
+    fn new(future: impl Future<Output = ()> + 'static) -> RawTask {
         // create a new Task, move the future to the heap and pin it
-        Task {
+        RawTask {
             id: TaskId::new(),
+            name: None,
+            priority: Priority::default(),
+            locals: TaskLocals::default(),
             future: Box::pin(future),
         }
     }
 
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        let future = self.future.as_mut();
+        self.locals.enter(|| future.poll(context))
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
@@ -40,4 +193,8 @@ impl TaskId {
         // every ID is returned exactly once
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
-}
\ No newline at end of file
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
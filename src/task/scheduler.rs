@@ -0,0 +1,140 @@
+/* scheduling policies of the Executor
+
+   each priority level has its own ready queue, the policy picks the level of the next task to poll
+   ready tasks are identified by a ticket, a counter incremented on each wakeup, so the policies
+   can tell how long a level has been waiting (in number of wakeups) without reading a clock
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+    pub const ALL: [Priority; Priority::COUNT] = [Priority::Low, Priority::Normal, Priority::High];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+pub trait SchedulingPolicy {
+    // `oldest_ticket[priority.index()]` is the ticket of the task waiting for the longest time at
+    // this priority, None if no task is ready, at least one is Some
+    // `ticket` is the current value of the counter
+    // picking a level without ready task is a bug, the executor runs the highest ready level instead
+    fn pick(&mut self, oldest_ticket: &[Option<u64>; Priority::COUNT], ticket: u64) -> Priority;
+
+    // called after each poll of a task of the picked priority
+    fn polled(&mut self, _priority: Priority) {}
+}
+
+// in the order the tasks were woken, ignoring priorities
+pub struct Fifo;
+
+impl SchedulingPolicy for Fifo {
+    fn pick(&mut self, oldest_ticket: &[Option<u64>; Priority::COUNT], _ticket: u64) -> Priority {
+        Priority::ALL.iter()
+            .filter_map(|&priority| oldest_ticket[priority.index()].map(|ticket| (ticket, priority)))
+            .min()
+            .unwrap()
+            .1
+    }
+}
+
+// highest priority first, but a level gains one priority for every `aging` wakeups its oldest task
+// waited, so a busy high priority task can't starve the others
+pub struct PriorityAging {
+    pub aging: u64,
+}
+
+impl PriorityAging {
+    pub const DEFAULT_AGING: u64 = 64;
+}
+
+impl Default for PriorityAging {
+    fn default() -> Self {
+        PriorityAging { aging: PriorityAging::DEFAULT_AGING }
+    }
+}
+
+impl SchedulingPolicy for PriorityAging {
+    fn pick(&mut self, oldest_ticket: &[Option<u64>; Priority::COUNT], ticket: u64) -> Priority {
+        let aging = self.aging.max(1);
+        // max_by_key() returns the last maximum, so on a tie the higher priority wins
+        Priority::ALL.iter()
+            .filter_map(|&priority| {
+                oldest_ticket[priority.index()].map(|oldest| {
+                    let boost = (ticket - oldest) / aging;
+                    (priority.index() as u64 + boost, priority)
+                })
+            })
+            .max_by_key(|&(effective, _)| effective)
+            .unwrap()
+            .1
+    }
+}
+
+// stride scheduling: while several levels have ready tasks, each gets a share of the polls
+// proportional to its weight
+pub struct WeightedFair {
+    weights: [u64; Priority::COUNT],
+    // virtual time of each level, increased by STRIDE / weight on each poll
+    pass: [u64; Priority::COUNT],
+    // pass of the last picked level, levels which were idle restart from there
+    virtual_time: u64,
+}
+
+impl WeightedFair {
+    const STRIDE: u64 = 1 << 20;
+
+    // weights indexed by `Priority::index`, must not be 0
+    pub fn new(weights: [u64; Priority::COUNT]) -> Self {
+        assert!(weights.iter().all(|&weight| weight > 0));
+        WeightedFair {
+            weights,
+            pass: [0; Priority::COUNT],
+            virtual_time: 0,
+        }
+    }
+}
+
+impl Default for WeightedFair {
+    fn default() -> Self {
+        WeightedFair::new([1, 2, 4])
+    }
+}
+
+impl SchedulingPolicy for WeightedFair {
+    fn pick(&mut self, oldest_ticket: &[Option<u64>; Priority::COUNT], _ticket: u64) -> Priority {
+        let mut picked: Option<Priority> = None;
+        for &priority in Priority::ALL.iter().rev() {
+            if oldest_ticket[priority.index()].is_none() {
+                continue;
+            }
+            // an idle level doesn't get credit for the time it wasn't ready
+            let index = priority.index();
+            self.pass[index] = self.pass[index].max(self.virtual_time);
+            if picked.map_or(true, |picked| self.pass[index] < self.pass[picked.index()]) {
+                picked = Some(priority);
+            }
+        }
+
+        let picked = picked.unwrap();
+        self.virtual_time = self.pass[picked.index()];
+        picked
+    }
+
+    fn polled(&mut self, priority: Priority) {
+        self.pass[priority.index()] += WeightedFair::STRIDE / self.weights[priority.index()];
+    }
+}
@@ -0,0 +1,53 @@
+use super::{TaskId, scheduler::Priority};
+
+// polls longer than this are reported on serial by default, about 20ms at 2.5GHz
+pub const DEFAULT_SLOW_POLL_CYCLES: u64 = 50_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    // woken, waiting to be polled
+    Ready,
+    // waiting to be woken
+    Waiting,
+}
+
+// durations are in TSC cycles
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaskStats {
+    pub polls: u64,
+    pub wakes: u64,
+    pub total_poll_cycles: u64,
+    pub max_poll_cycles: u64,
+    // polls longer than the slow poll threshold of the executor
+    pub slow_polls: u64,
+}
+
+impl TaskStats {
+    pub fn mean_poll_cycles(&self) -> u64 {
+        if self.polls == 0 {
+            0
+        } else {
+            self.total_poll_cycles / self.polls
+        }
+    }
+
+    pub(super) fn record_poll(&mut self, cycles: u64) {
+        self.polls += 1;
+        self.total_poll_cycles += cycles;
+        self.max_poll_cycles = self.max_poll_cycles.max(cycles);
+    }
+}
+
+// a live task, as returned by `Executor::task_list` and `TaskList::get`
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<&'static str>,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub stats: TaskStats,
+}
+
+pub(super) fn timestamp() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
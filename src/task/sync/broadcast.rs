@@ -0,0 +1,203 @@
+/* multi-producer multi-consumer channel, every receiver gets every value
+
+   - the last `capacity` values are kept, a receiver which falls further behind gets
+     `RecvError::Lagged` with the number of values it missed, then continues with the oldest kept
+   - the buffer is allocated up front, so `Sender::send` never allocates and can be used from
+     interrupt handlers (but the value it pushes out of the buffer is dropped there)
+*/
+
+use super::{locked, WaitList};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    // every sender is dropped and the receiver got all the values
+    Closed,
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "the capacity of a channel can't be 0");
+    let shared = Arc::new(spin::Mutex::new(Shared {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        next_position: 0,
+        senders: 1,
+        receivers: 1,
+        waiters: WaitList::new(),
+    }));
+    (Sender { shared: shared.clone() }, Receiver { shared, position: 0 })
+}
+
+struct Shared<T> {
+    // the last values sent, the last one is at `next_position - 1`
+    buffer: VecDeque<T>,
+    capacity: usize,
+    // position of the next value sent
+    next_position: u64,
+    senders: usize,
+    receivers: usize,
+    waiters: WaitList,
+}
+
+impl<T> Shared<T> {
+    fn first_position(&self) -> u64 {
+        self.next_position - self.buffer.len() as u64
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<spin::Mutex<Shared<T>>>,
+}
+
+impl<T: Clone> Sender<T> {
+    // returns the number of receivers, gives the value back if there are none
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let oldest = locked(&self.shared, |shared| {
+            if shared.receivers == 0 {
+                return Err(SendError(value));
+            }
+            let oldest = if shared.buffer.len() == shared.capacity {
+                shared.buffer.pop_front()
+            } else {
+                None
+            };
+            shared.buffer.push_back(value);
+            shared.next_position += 1;
+            shared.waiters.wake_all();
+            Ok(oldest)
+        })?;
+        drop(oldest);
+        Ok(self.receiver_count())
+    }
+
+    // the receiver gets the values sent from now on
+    pub fn subscribe(&self) -> Receiver<T> {
+        let position = locked(&self.shared, |shared| {
+            shared.receivers += 1;
+            shared.next_position
+        });
+        Receiver { shared: self.shared.clone(), position }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        locked(&self.shared, |shared| shared.receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        locked(&self.shared, |shared| shared.senders += 1);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        locked(&self.shared, |shared| {
+            shared.senders -= 1;
+            if shared.senders == 0 {
+                shared.waiters.wake_all();
+            }
+        });
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<spin::Mutex<Shared<T>>>,
+    // position of the next value to receive
+    position: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    pub fn recv(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture { receiver: self, key: None }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let position = &mut self.position;
+        locked(&self.shared, |shared| Receiver::next(shared, position))
+    }
+
+    fn next(shared: &mut Shared<T>, position: &mut u64) -> Result<T, TryRecvError> {
+        let first_position = shared.first_position();
+        if *position < first_position {
+            let missed = first_position - *position;
+            *position = first_position;
+            Err(TryRecvError::Lagged(missed))
+        } else if *position < shared.next_position {
+            let value = shared.buffer[(*position - first_position) as usize].clone();
+            *position += 1;
+            Ok(value)
+        } else if shared.senders == 0 {
+            Err(TryRecvError::Closed)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+}
+
+// the new receiver is at the same position
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        locked(&self.shared, |shared| shared.receivers += 1);
+        Receiver { shared: self.shared.clone(), position: self.position }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        locked(&self.shared, |shared| shared.receivers -= 1);
+    }
+}
+
+pub struct RecvFuture<'a, T> {
+    receiver: &'a mut Receiver<T>,
+    key: Option<u64>,
+}
+
+impl<T: Clone> Future for RecvFuture<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let this = &mut *self;
+        let key = &mut this.key;
+        let position = &mut this.receiver.position;
+        locked(&this.receiver.shared, |shared| {
+            let result = match Receiver::next(shared, position) {
+                Ok(value) => Ok(value),
+                Err(TryRecvError::Lagged(missed)) => Err(RecvError::Lagged(missed)),
+                Err(TryRecvError::Closed) => Err(RecvError::Closed),
+                Err(TryRecvError::Empty) => {
+                    shared.waiters.register(key, cx.waker());
+                    return Poll::Pending;
+                }
+            };
+            shared.waiters.remove(key);
+            Poll::Ready(result)
+        })
+    }
+}
+
+impl<T> Drop for RecvFuture<'_, T> {
+    fn drop(&mut self) {
+        let key = &mut self.key;
+        // every waiting receiver is woken by a send, there is nothing to pass on
+        locked(&self.receiver.shared, |shared| shared.waiters.remove(key));
+    }
+}
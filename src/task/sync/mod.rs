@@ -0,0 +1,125 @@
+/* async synchronization primitives for the tasks
+
+   - the state of each primitive is behind a spin lock taken with interrupts disabled, so the
+     operations which never wait nor allocate can be used from interrupt handlers:
+     `mpsc::Sender::try_send`, `oneshot::Sender::send`, `broadcast::Sender::send`,
+     `Semaphore::add_permits`, `Notify::notify_one` and `Notify::notify_waiters`
+   - a waiting future registers the waker of its task in a WaitList, it stays in the list
+     (keeping its place) until it completes or is dropped
+   - a future dropped after being woken by `wake_one` passes the wakeup on to the next waiter,
+     so it isn't lost
+*/
+
+use alloc::collections::VecDeque;
+use core::task::Waker;
+use x86_64::instructions::interrupts;
+
+pub mod mpsc;
+pub mod oneshot;
+pub mod broadcast;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod notify;
+
+pub use mutex::{Mutex, MutexGuard, MutexLock};
+pub use rwlock::{RwLock, RwLockRead, RwLockReadGuard, RwLockWrite, RwLockWriteGuard};
+pub use semaphore::{Semaphore, SemaphoreAcquire, SemaphorePermit};
+pub use notify::{Notify, Notified};
+
+// run `f` with the lock held, an interrupt handler taking it while we hold it would deadlock
+fn locked<T, R>(lock: &spin::Mutex<T>, f: impl FnOnce(&mut T) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut lock.lock()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Wakeup {
+    // by `wake_one`, passed on if the waiter is dropped
+    One,
+    // by `wake_all`
+    All,
+}
+
+struct Waiter {
+    key: u64,
+    waker: Waker,
+    woken: Option<Wakeup>,
+}
+
+// the tasks waiting on a primitive, in the order they started waiting
+// each waiting future identifies itself with a key, None while it isn't in the list
+struct WaitList {
+    next_key: u64,
+    waiters: VecDeque<Waiter>,
+}
+
+impl WaitList {
+    fn new() -> Self {
+        WaitList {
+            next_key: 0,
+            waiters: VecDeque::new(),
+        }
+    }
+
+    fn position(&self, key: Option<u64>) -> Option<usize> {
+        let key = key?;
+        self.waiters.iter().position(|waiter| waiter.key == key)
+    }
+
+    // add the waiter or, if it is already in the list, mark it as waiting again with its new waker
+    fn register(&mut self, key: &mut Option<u64>, waker: &Waker) {
+        match self.position(*key) {
+            Some(index) => {
+                let waiter = &mut self.waiters[index];
+                waiter.woken = None;
+                if !waiter.waker.will_wake(waker) {
+                    waiter.waker = waker.clone();
+                }
+            }
+            None => {
+                let new_key = self.next_key;
+                self.next_key += 1;
+                self.waiters.push_back(Waiter {
+                    key: new_key,
+                    waker: waker.clone(),
+                    woken: None,
+                });
+                *key = Some(new_key);
+            }
+        }
+    }
+
+    // how the waiter was woken since it last registered, None if it wasn't
+    fn wakeup(&self, key: Option<u64>) -> Option<Wakeup> {
+        self.position(key).and_then(|index| self.waiters[index].woken)
+    }
+
+    // remove the waiter, returns how it was woken
+    fn remove(&mut self, key: &mut Option<u64>) -> Option<Wakeup> {
+        let index = self.position(key.take())?;
+        self.waiters.remove(index).and_then(|waiter| waiter.woken)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+
+    // wake the first waiter not woken yet, returns false if there is none
+    fn wake_one(&mut self) -> bool {
+        match self.waiters.iter_mut().find(|waiter| waiter.woken.is_none()) {
+            Some(waiter) => {
+                waiter.woken = Some(Wakeup::One);
+                waiter.waker.wake_by_ref();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for waiter in self.waiters.iter_mut().filter(|waiter| waiter.woken.is_none()) {
+            waiter.woken = Some(Wakeup::All);
+            waiter.waker.wake_by_ref();
+        }
+    }
+}
@@ -0,0 +1,304 @@
+/* multi-producer single-consumer channels
+
+   - `channel(capacity)`: `Sender::send` waits while the channel is full, `Sender::try_send`
+     doesn't wait and, as the queue is allocated up front, never allocates: it can be used from
+     interrupt handlers
+   - `unbounded_channel()`: `UnboundedSender::send` never waits but allocates when the queue grows
+   - the channel is closed when the receiver is dropped or closed, or when every sender is dropped
+     (the receiver still gets the values sent before)
+*/
+
+use super::{locked, WaitList, Wakeup};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::stream::Stream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    // every sender is dropped and the channel is empty
+    Disconnected,
+}
+
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "the capacity of a channel can't be 0");
+    let chan = Chan::new(Some(capacity));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let chan = Chan::new(None);
+    (UnboundedSender { chan: chan.clone() }, Receiver { chan })
+}
+
+struct Chan<T> {
+    state: spin::Mutex<ChanState<T>>,
+    // None if unbounded
+    capacity: Option<usize>,
+}
+
+struct ChanState<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    closed: bool,
+    receiver_waker: Option<Waker>,
+    // senders waiting for room in the queue
+    send_waiters: WaitList,
+}
+
+impl<T> ChanState<T> {
+    fn wake_receiver(&mut self) {
+        if let Some(waker) = self.receiver_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Chan<T> {
+    fn new(capacity: Option<usize>) -> Arc<Self> {
+        let queue = match capacity {
+            Some(capacity) => VecDeque::with_capacity(capacity),
+            None => VecDeque::new(),
+        };
+        Arc::new(Chan {
+            state: spin::Mutex::new(ChanState {
+                queue,
+                senders: 1,
+                closed: false,
+                receiver_waker: None,
+                send_waiters: WaitList::new(),
+            }),
+            capacity,
+        })
+    }
+
+    fn push(&self, state: &mut ChanState<T>, value: T) -> Result<(), TrySendError<T>> {
+        if state.closed {
+            Err(TrySendError::Closed(value))
+        } else if self.capacity.map_or(false, |capacity| state.queue.len() >= capacity) {
+            Err(TrySendError::Full(value))
+        } else {
+            state.queue.push_back(value);
+            state.wake_receiver();
+            Ok(())
+        }
+    }
+
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        locked(&self.state, |state| self.push(state, value))
+    }
+
+    fn is_closed(&self) -> bool {
+        locked(&self.state, |state| state.closed)
+    }
+
+    fn add_sender(&self) {
+        locked(&self.state, |state| state.senders += 1);
+    }
+
+    fn drop_sender(&self) {
+        locked(&self.state, |state| {
+            state.senders -= 1;
+            if state.senders == 0 {
+                state.wake_receiver();
+            }
+        });
+    }
+}
+
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    // resolves to an error giving the value back if the channel is closed
+    pub fn send(&self, value: T) -> SendFuture<'_, T> {
+        SendFuture {
+            chan: &self.chan,
+            value: Some(value),
+            key: None,
+        }
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.chan.try_send(value)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.is_closed()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        Sender { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+pub struct SendFuture<'a, T> {
+    chan: &'a Chan<T>,
+    value: Option<T>,
+    key: Option<u64>,
+}
+
+// the value is never pinned, it is only moved out of the future
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        let this = &mut *self;
+        let chan = this.chan;
+        let value = this.value.take().expect("SendFuture polled after completion");
+        locked(&chan.state, |state| match chan.push(state, value) {
+            Ok(()) => {
+                state.send_waiters.remove(&mut this.key);
+                Poll::Ready(Ok(()))
+            }
+            Err(TrySendError::Closed(value)) => {
+                state.send_waiters.remove(&mut this.key);
+                Poll::Ready(Err(SendError(value)))
+            }
+            Err(TrySendError::Full(value)) => {
+                this.value = Some(value);
+                state.send_waiters.register(&mut this.key, cx.waker());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<T> Drop for SendFuture<'_, T> {
+    fn drop(&mut self) {
+        let key = &mut self.key;
+        locked(&self.chan.state, |state| {
+            if state.send_waiters.remove(key) == Some(Wakeup::One) {
+                state.send_waiters.wake_one();
+            }
+        });
+    }
+}
+
+pub struct UnboundedSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> UnboundedSender<T> {
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.chan.try_send(value).map_err(|err| match err {
+            TrySendError::Closed(value) | TrySendError::Full(value) => SendError(value),
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.is_closed()
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        UnboundedSender { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    // resolves to None once every sender is dropped and the channel is empty
+    pub fn recv(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        locked(&self.chan.state, |state| match state.queue.pop_front() {
+            Some(value) => {
+                state.send_waiters.wake_one();
+                Ok(value)
+            }
+            None if state.senders == 0 || state.closed => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        })
+    }
+
+    // the senders fail from now on, the values already sent can still be received
+    pub fn close(&mut self) {
+        locked(&self.chan.state, |state| {
+            state.closed = true;
+            state.send_waiters.wake_all();
+        });
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        locked(&self.chan.state, |state| match state.queue.pop_front() {
+            Some(value) => {
+                state.send_waiters.wake_one();
+                Poll::Ready(Some(value))
+            }
+            None if state.senders == 0 || state.closed => Poll::Ready(None),
+            None => {
+                state.receiver_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+        // drop the values left outside of the lock
+        let queue = locked(&self.chan.state, |state| mem::take(&mut state.queue));
+        drop(queue);
+    }
+}
+
+pub struct RecvFuture<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for RecvFuture<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.receiver.poll_recv(cx)
+    }
+}
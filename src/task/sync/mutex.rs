@@ -0,0 +1,128 @@
+use super::{locked, WaitList, Wakeup};
+use core::{
+    cell::{Cell, UnsafeCell},
+    future::Future,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// a mutex whose `lock` waits asynchronously, so the guard can be held across an await
+pub struct Mutex<T> {
+    state: spin::Mutex<MutexState>,
+    value: UnsafeCell<T>,
+}
+
+struct MutexState {
+    locked: bool,
+    waiters: WaitList,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Self {
+        Mutex {
+            state: spin::Mutex::new(MutexState {
+                locked: false,
+                waiters: WaitList::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexLock<'_, T> {
+        MutexLock { mutex: self, key: None }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        locked(&self.state, |state| {
+            if state.locked {
+                None
+            } else {
+                state.locked = true;
+                Some(MutexGuard { mutex: self, _not_sync: PhantomData })
+            }
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn unlock(&self) {
+        locked(&self.state, |state| {
+            state.locked = false;
+            state.waiters.wake_one();
+        });
+    }
+}
+
+pub struct MutexLock<'a, T> {
+    mutex: &'a Mutex<T>,
+    key: Option<u64>,
+}
+
+impl<'a, T> Future for MutexLock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<MutexGuard<'a, T>> {
+        let this = &mut *self;
+        let mutex = this.mutex;
+        locked(&mutex.state, |state| {
+            if state.locked {
+                state.waiters.register(&mut this.key, cx.waker());
+                Poll::Pending
+            } else {
+                state.locked = true;
+                state.waiters.remove(&mut this.key);
+                Poll::Ready(MutexGuard { mutex, _not_sync: PhantomData })
+            }
+        })
+    }
+}
+
+impl<T> Drop for MutexLock<'_, T> {
+    fn drop(&mut self) {
+        let key = &mut self.key;
+        locked(&self.mutex.state, |state| {
+            if state.waiters.remove(key) == Some(Wakeup::One) {
+                state.waiters.wake_one();
+            }
+        });
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    // `&Mutex<T>` is Sync as soon as T is Send, but a shared guard gives out `&T`
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
@@ -0,0 +1,91 @@
+use super::{locked, WaitList, Wakeup};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// wakes tasks waiting for an event, without data
+// `notify_one` with no waiting task stores a permit, so the next `notified` completes at once
+pub struct Notify {
+    state: spin::Mutex<NotifyState>,
+}
+
+struct NotifyState {
+    permit: bool,
+    waiters: WaitList,
+}
+
+impl Notify {
+    pub fn new() -> Self {
+        Notify {
+            state: spin::Mutex::new(NotifyState {
+                permit: false,
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    // the future waits from its first poll
+    pub fn notified(&self) -> Notified<'_> {
+        Notified { notify: self, key: None }
+    }
+
+    // can be called from an interrupt handler
+    pub fn notify_one(&self) {
+        locked(&self.state, |state| {
+            if !state.waiters.wake_one() {
+                state.permit = true;
+            }
+        });
+    }
+
+    // wake the tasks waiting now, doesn't store a permit
+    // can be called from an interrupt handler
+    pub fn notify_waiters(&self) {
+        locked(&self.state, |state| state.waiters.wake_all());
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    key: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        locked(&this.notify.state, |state| {
+            if state.waiters.wakeup(this.key).is_some() {
+                state.waiters.remove(&mut this.key);
+                Poll::Ready(())
+            } else if this.key.is_none() && state.permit {
+                state.permit = false;
+                Poll::Ready(())
+            } else {
+                state.waiters.register(&mut this.key, cx.waker());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let key = &mut self.key;
+        locked(&self.notify.state, |state| {
+            // the notification was meant for one task, give it to another one
+            if state.waiters.remove(key) == Some(Wakeup::One) && !state.waiters.wake_one() {
+                state.permit = true;
+            }
+        });
+    }
+}
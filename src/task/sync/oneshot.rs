@@ -0,0 +1,117 @@
+// a channel for a single value, `Sender::send` can be used from interrupt handlers
+
+use super::locked;
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+// the sender was dropped without sending
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(spin::Mutex::new(Inner {
+        value: None,
+        sender_alive: true,
+        receiver_alive: true,
+        receiver_waker: None,
+    }));
+    (Sender { inner: inner.clone() }, Receiver { inner })
+}
+
+struct Inner<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    receiver_waker: Option<Waker>,
+}
+
+impl<T> Inner<T> {
+    fn wake_receiver(&mut self) {
+        if let Some(waker) = self.receiver_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+pub struct Sender<T> {
+    inner: Arc<spin::Mutex<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+    // gives the value back if the receiver is dropped
+    pub fn send(self, value: T) -> Result<(), T> {
+        locked(&self.inner, |inner| {
+            if inner.receiver_alive {
+                inner.value = Some(value);
+                inner.wake_receiver();
+                Ok(())
+            } else {
+                Err(value)
+            }
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        locked(&self.inner, |inner| !inner.receiver_alive)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        locked(&self.inner, |inner| {
+            inner.sender_alive = false;
+            inner.wake_receiver();
+        });
+    }
+}
+
+// resolves to the value, or to an error if the sender is dropped without sending
+pub struct Receiver<T> {
+    inner: Arc<spin::Mutex<Inner<T>>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        locked(&self.inner, |inner| match inner.value.take() {
+            Some(value) => Ok(value),
+            None if inner.sender_alive => Err(TryRecvError::Empty),
+            None => Err(TryRecvError::Closed),
+        })
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        locked(&self.inner, |inner| match inner.value.take() {
+            Some(value) => Poll::Ready(Ok(value)),
+            None if inner.sender_alive => {
+                inner.receiver_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            None => Poll::Ready(Err(RecvError)),
+        })
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let value = locked(&self.inner, |inner| {
+            inner.receiver_alive = false;
+            inner.value.take()
+        });
+        // dropped outside of the lock
+        drop(value);
+    }
+}
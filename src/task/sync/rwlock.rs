@@ -0,0 +1,214 @@
+use super::{locked, WaitList, Wakeup};
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// many readers or a single writer
+// waiting writers have priority: a new reader waits while a writer is waiting, so that a stream
+// of readers can't starve the writers
+pub struct RwLock<T> {
+    state: spin::Mutex<RwLockState>,
+    value: UnsafeCell<T>,
+}
+
+struct RwLockState {
+    readers: usize,
+    writer: bool,
+    read_waiters: WaitList,
+    write_waiters: WaitList,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl RwLockState {
+    fn can_read(&self) -> bool {
+        !self.writer && self.write_waiters.is_empty()
+    }
+
+    fn can_write(&self) -> bool {
+        !self.writer && self.readers == 0
+    }
+
+    // the lock is released or a writer stopped waiting
+    fn wake_next(&mut self) {
+        if !self.write_waiters.wake_one() && self.write_waiters.is_empty() {
+            self.read_waiters.wake_all();
+        }
+    }
+}
+
+impl<T> RwLock<T> {
+    pub fn new(value: T) -> Self {
+        RwLock {
+            state: spin::Mutex::new(RwLockState {
+                readers: 0,
+                writer: false,
+                read_waiters: WaitList::new(),
+                write_waiters: WaitList::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> RwLockRead<'_, T> {
+        RwLockRead { lock: self, key: None }
+    }
+
+    pub fn write(&self) -> RwLockWrite<'_, T> {
+        RwLockWrite { lock: self, key: None }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        locked(&self.state, |state| {
+            if state.can_read() {
+                state.readers += 1;
+                Some(RwLockReadGuard { lock: self })
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        locked(&self.state, |state| {
+            if state.can_write() {
+                state.writer = true;
+                Some(RwLockWriteGuard { lock: self })
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+pub struct RwLockRead<'a, T> {
+    lock: &'a RwLock<T>,
+    key: Option<u64>,
+}
+
+impl<'a, T> Future for RwLockRead<'a, T> {
+    type Output = RwLockReadGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<RwLockReadGuard<'a, T>> {
+        let this = &mut *self;
+        let lock = this.lock;
+        locked(&lock.state, |state| {
+            if state.can_read() {
+                state.readers += 1;
+                state.read_waiters.remove(&mut this.key);
+                Poll::Ready(RwLockReadGuard { lock })
+            } else {
+                state.read_waiters.register(&mut this.key, cx.waker());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<T> Drop for RwLockRead<'_, T> {
+    fn drop(&mut self) {
+        let key = &mut self.key;
+        // readers are woken all at once, there is nothing to pass on
+        locked(&self.lock.state, |state| state.read_waiters.remove(key));
+    }
+}
+
+pub struct RwLockWrite<'a, T> {
+    lock: &'a RwLock<T>,
+    key: Option<u64>,
+}
+
+impl<'a, T> Future for RwLockWrite<'a, T> {
+    type Output = RwLockWriteGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<RwLockWriteGuard<'a, T>> {
+        let this = &mut *self;
+        let lock = this.lock;
+        locked(&lock.state, |state| {
+            if state.can_write() {
+                state.writer = true;
+                state.write_waiters.remove(&mut this.key);
+                Poll::Ready(RwLockWriteGuard { lock })
+            } else {
+                state.write_waiters.register(&mut this.key, cx.waker());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<T> Drop for RwLockWrite<'_, T> {
+    fn drop(&mut self) {
+        let key = &mut self.key;
+        locked(&self.lock.state, |state| {
+            let wakeup = state.write_waiters.remove(key);
+            // the readers may have been waiting for this writer only
+            if wakeup == Some(Wakeup::One) || (state.write_waiters.is_empty() && !state.writer) {
+                state.wake_next();
+            }
+        });
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        locked(&self.lock.state, |state| {
+            state.readers -= 1;
+            if state.readers == 0 {
+                state.wake_next();
+            }
+        });
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        locked(&self.lock.state, |state| {
+            state.writer = false;
+            state.wake_next();
+        });
+    }
+}
@@ -0,0 +1,111 @@
+use super::{locked, WaitList, Wakeup};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// a counter of permits, `acquire` waits until one is available
+pub struct Semaphore {
+    state: spin::Mutex<SemaphoreState>,
+}
+
+struct SemaphoreState {
+    permits: usize,
+    waiters: WaitList,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            state: spin::Mutex::new(SemaphoreState {
+                permits,
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        locked(&self.state, |state| state.permits)
+    }
+
+    // can be called from an interrupt handler
+    pub fn add_permits(&self, count: usize) {
+        locked(&self.state, |state| {
+            state.permits += count;
+            for _ in 0..count {
+                if !state.waiters.wake_one() {
+                    break;
+                }
+            }
+        });
+    }
+
+    pub fn acquire(&self) -> SemaphoreAcquire<'_> {
+        SemaphoreAcquire { semaphore: self, key: None }
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        locked(&self.state, |state| {
+            if state.permits > 0 {
+                state.permits -= 1;
+                Some(SemaphorePermit { semaphore: self })
+            } else {
+                None
+            }
+        })
+    }
+}
+
+pub struct SemaphoreAcquire<'a> {
+    semaphore: &'a Semaphore,
+    key: Option<u64>,
+}
+
+impl<'a> Future for SemaphoreAcquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let this = &mut *self;
+        let semaphore = this.semaphore;
+        locked(&semaphore.state, |state| {
+            if state.permits > 0 {
+                state.permits -= 1;
+                state.waiters.remove(&mut this.key);
+                Poll::Ready(SemaphorePermit { semaphore })
+            } else {
+                state.waiters.register(&mut this.key, cx.waker());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for SemaphoreAcquire<'_> {
+    fn drop(&mut self) {
+        let key = &mut self.key;
+        locked(&self.semaphore.state, |state| {
+            if state.waiters.remove(key) == Some(Wakeup::One) {
+                state.waiters.wake_one();
+            }
+        });
+    }
+}
+
+// the permit is given back when dropped
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl SemaphorePermit<'_> {
+    // keep the permit out of the semaphore for good
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
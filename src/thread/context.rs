@@ -0,0 +1,54 @@
+/* switch between the stacks of two threads
+
+   `switch_context` pushes the callee-saved registers on the current stack, saves the stack
+   pointer in `*old_rsp`, loads `new_rsp` and pops the registers of the next thread, then returns
+   to where that thread called `switch_context`
+   the caller-saved registers are saved by the compiler around the call, and the kernel has no
+   SSE/FPU state (soft-float), so this is the whole context of a thread
+
+   always called with interrupts disabled
+*/
+
+global_asm!("
+    .intel_syntax noprefix
+    .global switch_context
+    switch_context:
+        push rbp
+        push rbx
+        push r12
+        push r13
+        push r14
+        push r15
+        mov [rdi], rsp
+        mov rsp, rsi
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        pop rbp
+        ret
+    .att_syntax prefix
+");
+
+extern "C" {
+    pub(super) fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+// callee-saved registers pushed by `switch_context`
+const SAVED_REGISTERS: usize = 6;
+
+// prepare the stack of a new thread so that switching to it starts `entry`
+// `stack_top` must be 16 bytes aligned, returns the initial stack pointer
+pub(super) unsafe fn init_stack(stack_top: u64, entry: extern "C" fn() -> !) -> u64 {
+    let top = stack_top as *mut u64;
+    // return address of `entry`, never used: rsp + 8 is aligned at the start of `entry` as if called
+    top.sub(1).write(0);
+    // popped by the `ret` of `switch_context`
+    top.sub(2).write(entry as u64);
+    // initial value of the callee-saved registers
+    for i in 0..SAVED_REGISTERS {
+        top.sub(3 + i).write(0);
+    }
+    top.sub(2 + SAVED_REGISTERS) as u64
+}
@@ -0,0 +1,161 @@
+use alloc::{boxed::Box, sync::Arc};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+
+/* kernel threads, preempted by the timer interrupt
+
+   - the boot thread (running `kernel_main` and the Executor) is the first thread, the others
+     run on the stacks mapped by `init`
+   - the timer interrupt is raised TIMER_HZ times per second, each one switches to the next
+     ready thread (round robin)
+   - the async tasks keep running cooperatively inside the thread of their Executor
+   - the threads only run on the bootstrap CPU: `current`, `yield_now`, `sleep`, `block` and
+     `JoinHandle::join` panic when called on an AP (from a SendTask or a job given with
+     `smp::run_on`), `spawn` and `wake` can be called from any CPU
+*/
+
+mod context;
+mod scheduler;
+mod stack;
+
+pub use stack::{STACK_SIZE, THREAD_STACKS_START};
+
+// including the boot thread and the idle thread
+pub const MAX_THREADS: usize = 16;
+pub const TIMER_HZ: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        // every ID is returned exactly once
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    NotInitialized,
+    TooManyThreads,
+}
+
+// map the thread stacks, speed up the timer and turn the current code into the boot thread
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>
+) -> Result<(), MapToError<Size4KiB>> {
+    stack::init(mapper, frame_allocator)?;
+    scheduler::init();
+    crate::interrupts::set_timer_frequency(TIMER_HZ);
+    Ok(())
+}
+
+pub fn spawn<F, T>(f: F) -> Result<JoinHandle<T>, SpawnError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet {
+        output: spin::Mutex::new(None),
+        finished: AtomicBool::new(false),
+        joiner: spin::Mutex::new(None),
+    });
+    let thread_packet = packet.clone();
+    let id = scheduler::spawn(Box::new(move || thread_packet.finish(f())))?;
+    Ok(JoinHandle { id, packet })
+}
+
+pub fn current() -> ThreadId {
+    scheduler::current()
+}
+
+pub fn yield_now() {
+    scheduler::yield_now();
+}
+
+// timer interrupts since boot
+pub fn ticks() -> u64 {
+    scheduler::ticks()
+}
+
+// at least `ms` milliseconds, rounded up to whole ticks
+pub fn sleep(ms: u64) {
+    let ticks = (ms * u64::from(TIMER_HZ) + 999) / 1000;
+    // the current tick is already partly over, it doesn't count
+    let until = scheduler::ticks() + ticks + 1;
+    scheduler::sleep_until(until);
+    // without threads, wait for the timer interrupts
+    while scheduler::ticks() < until {
+        x86_64::instructions::hlt();
+    }
+}
+
+// stop running until `wake` is called with the current thread
+// returns at once if it was called since the last `block`
+pub fn block() {
+    scheduler::block();
+}
+
+// can be called from an interrupt handler
+pub fn wake(id: ThreadId) {
+    scheduler::wake(id);
+}
+
+// bounds of the stack of the thread running on the bootstrap CPU, doesn't take the scheduler lock
+pub(crate) fn current_stack() -> Range<usize> {
+    scheduler::current_stack()
+}
+
+pub(crate) fn timer_tick() {
+    scheduler::tick();
+}
+
+// shared between a thread and its JoinHandle
+struct Packet<T> {
+    output: spin::Mutex<Option<T>>,
+    finished: AtomicBool,
+    // thread waiting in `join`
+    joiner: spin::Mutex<Option<ThreadId>>,
+}
+
+impl<T> Packet<T> {
+    fn finish(&self, output: T) {
+        *self.output.lock() = Some(output);
+        self.finished.store(true, Ordering::SeqCst);
+        if let Some(joiner) = *self.joiner.lock() {
+            wake(joiner);
+        }
+    }
+}
+
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread_id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.finished.load(Ordering::SeqCst)
+    }
+
+    // block the current thread until the thread returns
+    pub fn join(self) -> T {
+        *self.packet.joiner.lock() = Some(current());
+        // `block` returns at once if the thread finished (and woke us) after this check
+        while !self.is_finished() {
+            block();
+        }
+        self.packet.output.lock().take().expect("thread output already taken")
+    }
+}
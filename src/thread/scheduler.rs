@@ -0,0 +1,288 @@
+use super::{context, stack, SpawnError, ThreadId, MAX_THREADS};
+use crate::smp;
+use alloc::boxed::Box;
+use core::{ops::Range, sync::atomic::{AtomicUsize, Ordering}};
+use x86_64::instructions::interrupts;
+
+/* round robin scheduler
+
+   - the state is only locked with interrupts disabled, the timer interrupt handler switches threads
+   - the threads only run on the bootstrap CPU: the functions acting on the current thread panic
+     on an AP, `spawn` and `wake` can be called from any CPU
+   - the idle thread halts the CPU, it only runs when no other thread is ready
+   - a finished thread can't free its own stack while running on it, it is removed by the thread
+     switched to
+*/
+
+pub(super) type Entry = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ready,
+    Running,
+    Blocked,
+    // until the tick count reaches the value
+    Sleeping(u64),
+    Finished,
+}
+
+struct Thread {
+    id: ThreadId,
+    state: State,
+    // saved by `switch_context` while the thread isn't running
+    rsp: u64,
+    // None for the boot thread
+    stack: Option<usize>,
+    // taken when the thread starts
+    entry: Option<Entry>,
+    // `wake` was called while the thread wasn't blocked, its next `block` returns at once
+    wake_pending: bool,
+    // storage of the async task the thread was polling, see `task::local`
+    task_locals: usize,
+    // spawner of the executor the thread was running, see `task::spawn`
+    spawner: usize,
+}
+
+struct Scheduler {
+    threads: [Option<Thread>; MAX_THREADS],
+    current: usize,
+    idle: usize,
+    ticks: u64,
+    // thread to remove after the switch
+    finished: Option<usize>,
+    initialized: bool,
+}
+
+static SCHEDULER: spin::Mutex<Scheduler> = spin::Mutex::new(Scheduler::new());
+// stack slot + 1 of the current thread, 0 for the boot thread
+// read without the lock by the heap tracker, which can run while the lock is held
+static CURRENT_STACK: AtomicUsize = AtomicUsize::new(0);
+
+impl Scheduler {
+    const fn new() -> Self {
+        Scheduler {
+            threads: [None; MAX_THREADS],
+            current: 0,
+            idle: 0,
+            ticks: 0,
+            finished: None,
+            initialized: false,
+        }
+    }
+
+    fn current_mut(&mut self) -> &mut Thread {
+        self.threads[self.current].as_mut().expect("no current thread, thread::init wasn't called")
+    }
+
+    fn thread_mut(&mut self, id: ThreadId) -> Option<&mut Thread> {
+        self.threads.iter_mut().flatten().find(|thread| thread.id == id)
+    }
+
+    // the first ready thread after the current one, apart from the idle thread
+    fn next_ready(&self) -> Option<usize> {
+        (1..=MAX_THREADS)
+            .map(|offset| (self.current + offset) % MAX_THREADS)
+            .filter(|&index| index != self.idle)
+            .find(|&index| matches!(&self.threads[index], Some(thread) if thread.state == State::Ready))
+    }
+
+    fn add(&mut self, entry: Entry) -> Result<(usize, ThreadId), SpawnError> {
+        if !self.initialized {
+            return Err(SpawnError::NotInitialized);
+        }
+        let index = self.threads.iter().position(Option::is_none).ok_or(SpawnError::TooManyThreads)?;
+        let threads = &self.threads;
+        let slot = (0..stack::SLOTS)
+            .find(|&slot| !threads.iter().flatten().any(|thread| thread.stack == Some(slot)))
+            .ok_or(SpawnError::TooManyThreads)?;
+
+        let id = ThreadId::new();
+        self.threads[index] = Some(Thread {
+            id,
+            state: State::Ready,
+            rsp: unsafe { context::init_stack(stack::top(slot), thread_start) },
+            stack: Some(slot),
+            entry: Some(entry),
+            wake_pending: false,
+            task_locals: 0,
+            spawner: 0,
+        });
+        Ok((index, id))
+    }
+}
+
+// the boot thread becomes the current thread, and the idle thread is created
+pub(super) fn init() {
+    let idle: Entry = Box::new(|| loop {
+        x86_64::instructions::hlt();
+    });
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if scheduler.initialized {
+            return;
+        }
+        scheduler.threads[0] = Some(Thread {
+            id: ThreadId::new(),
+            state: State::Running,
+            rsp: 0,
+            stack: None,
+            entry: None,
+            wake_pending: false,
+            task_locals: 0,
+            spawner: 0,
+        });
+        scheduler.current = 0;
+        scheduler.initialized = true;
+        match scheduler.add(idle) {
+            Ok((index, _)) => scheduler.idle = index,
+            Err(_) => unreachable!("no room for the idle thread"),
+        }
+    });
+}
+
+pub(super) fn spawn(entry: Entry) -> Result<ThreadId, SpawnError> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().add(entry)).map(|(_, id)| id)
+}
+
+pub(super) fn current() -> ThreadId {
+    assert_on_bsp();
+    interrupts::without_interrupts(|| SCHEDULER.lock().current_mut().id)
+}
+
+pub(super) fn current_stack() -> Range<usize> {
+    match CURRENT_STACK.load(Ordering::Relaxed) {
+        0 => stack::boot_bounds(),
+        slot => stack::bounds(slot - 1),
+    }
+}
+
+pub(super) fn ticks() -> u64 {
+    interrupts::without_interrupts(|| SCHEDULER.lock().ticks)
+}
+
+// an AP has no current thread, it would switch away the one of the bootstrap CPU
+fn assert_on_bsp() {
+    assert_eq!(smp::cpu_index(), 0, "threads only run on the bootstrap CPU");
+}
+
+// switch to the next ready thread, `state` is the new state of the current thread
+// must be called with interrupts disabled, returns when the current thread is switched back to
+fn switch(state: State) {
+    assert_on_bsp();
+    let (old_rsp, new_rsp) = {
+        let mut scheduler = SCHEDULER.lock();
+        if !scheduler.initialized {
+            return;
+        }
+        let current = scheduler.current;
+        let next = match scheduler.next_ready() {
+            Some(next) => next,
+            // nothing else to run, keep running
+            None if state == State::Ready => return,
+            None => scheduler.idle,
+        };
+        if next == current {
+            return;
+        }
+
+        scheduler.current_mut().state = state;
+        if state == State::Finished {
+            scheduler.finished = Some(current);
+        }
+        scheduler.current = next;
+        let next_thread = scheduler.current_mut();
+        next_thread.state = State::Running;
+        let new_rsp = next_thread.rsp;
+        CURRENT_STACK.store(next_thread.stack.map_or(0, |slot| slot + 1), Ordering::Relaxed);
+        let task_locals = crate::task::local::swap_current(next_thread.task_locals as *mut _);
+        let spawner = crate::task::executor::swap_current_spawner(next_thread.spawner as *const _);
+        let current_thread = scheduler.threads[current].as_mut().unwrap();
+        current_thread.task_locals = task_locals as usize;
+        current_thread.spawner = spawner as usize;
+        let old_rsp = &mut current_thread.rsp as *mut u64;
+        (old_rsp, new_rsp)
+    };
+
+    // the thread table is static and the old thread is only removed after the switch
+    unsafe { context::switch_context(old_rsp, new_rsp) };
+    finish_switch();
+}
+
+// run by the thread switched to
+fn finish_switch() {
+    let mut scheduler = SCHEDULER.lock();
+    if let Some(index) = scheduler.finished.take() {
+        scheduler.threads[index] = None;
+    }
+}
+
+// first code run by a new thread, returned to by `switch_context`
+extern "C" fn thread_start() -> ! {
+    // interrupts are still disabled by the thread which switched here
+    finish_switch();
+    let entry = SCHEDULER.lock().current_mut().entry.take().expect("thread started twice");
+    interrupts::enable();
+    entry();
+    exit();
+}
+
+pub(super) fn exit() -> ! {
+    interrupts::disable();
+    switch(State::Finished);
+    unreachable!("a finished thread was switched to");
+}
+
+// called by the timer interrupt handler
+pub(super) fn tick() {
+    {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.ticks += 1;
+        let ticks = scheduler.ticks;
+        for thread in scheduler.threads.iter_mut().flatten() {
+            if let State::Sleeping(until) = thread.state {
+                if until <= ticks {
+                    thread.state = State::Ready;
+                }
+            }
+        }
+    }
+    switch(State::Ready);
+}
+
+pub(super) fn yield_now() {
+    assert_on_bsp();
+    interrupts::without_interrupts(|| switch(State::Ready));
+}
+
+// does nothing if the threads aren't initialized
+pub(super) fn sleep_until(until: u64) {
+    assert_on_bsp();
+    interrupts::without_interrupts(|| switch(State::Sleeping(until)));
+}
+
+pub(super) fn block() {
+    assert_on_bsp();
+    interrupts::without_interrupts(|| {
+        let wake_pending = {
+            let mut scheduler = SCHEDULER.lock();
+            let current = scheduler.current_mut();
+            core::mem::replace(&mut current.wake_pending, false)
+        };
+        if !wake_pending {
+            switch(State::Blocked);
+        }
+    });
+}
+
+pub(super) fn wake(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(thread) = scheduler.thread_mut(id) {
+            match thread.state {
+                State::Blocked => thread.state = State::Ready,
+                State::Finished => {}
+                _ => thread.wake_pending = true,
+            }
+        }
+    });
+}
@@ -0,0 +1,67 @@
+use super::MAX_THREADS;
+use core::ops::Range;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB
+    },
+    VirtAddr,
+};
+
+/* stacks of the threads
+
+   the slots are mapped at init from THREAD_STACKS_START, each one is an unmapped guard page
+   followed by STACK_SIZE bytes of stack: a stack overflow hits the guard page (page fault, then
+   double fault on its own stack) instead of the stack of another thread
+   the boot thread keeps the stack given by the bootloader, at the address set in Cargo.toml
+   ([package.metadata.bootloader]) so that its bounds are known
+   the scheduler keeps track of the slots in use
+*/
+
+pub const THREAD_STACKS_START: usize = 0x_6666_6666_0000;
+pub const STACK_SIZE: usize = 16 * 1024; // 16 KiB
+const PAGE_SIZE: usize = 4096;
+// must match kernel-stack-address and kernel-stack-size in Cargo.toml
+const BOOT_STACK_START: usize = 0x_3333_3333_0000;
+const BOOT_STACK_PAGES: usize = 512;
+const SLOT_SIZE: usize = PAGE_SIZE + STACK_SIZE;
+pub(super) const SLOTS: usize = MAX_THREADS - 1;
+
+pub(super) fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for slot in 0..SLOTS {
+        // skip the guard page
+        let stack_start = VirtAddr::new((THREAD_STACKS_START + slot * SLOT_SIZE + PAGE_SIZE) as u64);
+        let stack_end = stack_start + STACK_SIZE - 1u64;
+        let pages = Page::range_inclusive(
+            Page::<Size4KiB>::containing_address(stack_start),
+            Page::containing_address(stack_end),
+        );
+        for page in pages {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        }
+    }
+
+    Ok(())
+}
+
+// the stack grows down from the end of the slot
+pub(super) fn top(slot: usize) -> u64 {
+    (THREAD_STACKS_START + (slot + 1) * SLOT_SIZE) as u64
+}
+
+pub(super) fn bounds(slot: usize) -> Range<usize> {
+    let top = top(slot) as usize;
+    top - STACK_SIZE..top
+}
+
+// the bootloader leaves the first page unmapped as a guard page
+pub(super) fn boot_bounds() -> Range<usize> {
+    let start = BOOT_STACK_START + PAGE_SIZE;
+    start..start + BOOT_STACK_PAGES * PAGE_SIZE
+}
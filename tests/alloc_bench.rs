@@ -0,0 +1,160 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{QemuExitCode, exit_qemu, serial_println};
+use rust_os::allocator;
+
+/* allocator benchmarks, run with `cargo xtest --test alloc_bench [--release] --no-default-features --features <allocator>`
+
+   every workload runs RUNS times, one CSV line per workload is printed over serial:
+   bench,<allocator>,<workload>,<operations per run>,<min cycles>,<mean cycles>,<min cycles per operation>
+*/
+
+const RUNS: u64 = 5;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    serial_println!("bench,allocator,workload,ops,min_cycles,mean_cycles,cycles_per_op");
+    bench("small_object_churn", small_object_churn);
+    bench("vec_growth", vec_growth);
+    // the bump allocator can't reuse memory while other allocations are alive
+    if cfg!(feature = "bump") {
+        serial_println!("bench,bump,mixed_sizes,skipped");
+        serial_println!("bench,bump,producer_consumer,skipped");
+    } else {
+        bench("mixed_sizes", mixed_sizes);
+        bench("producer_consumer", producer_consumer);
+    }
+
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+// a workload returns the number of operations it made: calls to alloc, realloc or dealloc,
+// including those of the containers holding its objects
+fn bench(name: &str, workload: fn() -> u64) {
+    let mut min = u64::max_value();
+    let mut total = 0;
+    let mut ops = 0;
+    for _ in 0..RUNS {
+        let start = rdtsc();
+        ops = workload();
+        let cycles = rdtsc() - start;
+        min = min.min(cycles);
+        total += cycles;
+    }
+    serial_println!("bench,{},{},{},{},{},{}",
+        allocator::GLOBAL_ALLOCATOR, name, ops, min, total / RUNS, min / ops.max(1));
+}
+
+// volatile read of the first byte of `value`, which is in the heap allocation, so that
+// the allocation can't be optimized away. `value` can't be empty
+fn keep<T: ?Sized>(value: &T) {
+    unsafe { core::ptr::read_volatile(value as *const T as *const u8) };
+}
+
+// xorshift, the workloads must be the same for every allocator
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+// allocate and free small objects right away
+fn small_object_churn() -> u64 {
+    let n = 10_000;
+    for i in 0..n {
+        let x = Box::new(i);
+        keep(&*x);
+    }
+    // alloc + dealloc
+    n * 2
+}
+
+// push into a Vec from empty, reallocating as it grows
+fn vec_growth() -> u64 {
+    let mut ops = 0;
+    for _ in 0..10 {
+        let mut vec = Vec::new();
+        let mut capacity = vec.capacity();
+        for i in 0..4096u64 {
+            vec.push(i);
+            // the first alloc, then the reallocs
+            if vec.capacity() != capacity {
+                capacity = vec.capacity();
+                ops += 1;
+            }
+        }
+        keep(&vec[..]);
+        // dealloc
+        ops += 1;
+    }
+    ops
+}
+
+// random sizes from 8 bytes to 2 KiB, replacing random objects of a working set (at most 64 KiB)
+fn mixed_sizes() -> u64 {
+    const MAX_SIZE: usize = 2048;
+    // a quarter of the heap even if every object has the largest size, so that the workload
+    // fits whatever the fragmentation and still follows HEAP_SIZE
+    let slots = allocator::HEAP_SIZE / 4 / MAX_SIZE;
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut live: Vec<Vec<u8>> = (0..slots).map(|_| Vec::new()).collect();
+    let n = 5_000;
+    for _ in 0..n {
+        let size = MAX_SIZE >> (rng.next() % 9);
+        let slot = rng.next() as usize % live.len();
+        live[slot] = vec![0u8; size];
+        keep(&live[slot][..]);
+    }
+    // alloc + dealloc of each object, and of the `live` vec (empty vecs aren't allocated)
+    n * 2 + 2
+}
+
+// allocations freed in FIFO order by the consumer, in batches
+fn producer_consumer() -> u64 {
+    let mut queue: VecDeque<Box<[u8; 64]>> = VecDeque::with_capacity(64);
+    let n = 200;
+    for _ in 0..n {
+        for _ in 0..32 {
+            queue.push_back(Box::new([0; 64]));
+        }
+        for _ in 0..32 {
+            keep(&*queue.pop_front().unwrap());
+        }
+    }
+    // alloc + dealloc of each box, and of the queue which never grows
+    n * 64 + 2
+}
@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{serial_print, serial_println};
+use rust_os::dma::{self, CacheMode, DmaBuffer, DmaError, DmaVec, DMA_POOL_SIZE};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+
+    rust_os::init();
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    dma::init(&mut mapper, &mut frame_allocator).expect("DMA pool initialization failed");
+    unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset };
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// read through the mapping of the whole physical memory set up by the bootloader
+fn read_phys(phys: u64) -> u64 {
+    unsafe { ((PHYS_MEM_OFFSET + phys) as *const u64).read_volatile() }
+}
+
+#[test_case]
+fn buffer_phys_addr() {
+    serial_print!("buffer_phys_addr...");
+    for &mode in [CacheMode::Uncached, CacheMode::WriteBack].iter() {
+        let mut buffer = DmaBuffer::new(0u64, mode).unwrap();
+        *buffer = 0xdead_beef;
+        let phys = buffer.phys_addr().as_u64();
+        assert_eq!(phys % 4096, 0);
+        assert!(phys + 8 <= 1 << 32);
+        assert_eq!(read_phys(phys), 0xdead_beef);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn vec_is_contiguous() {
+    serial_print!("vec_is_contiguous...");
+    let mut vec = DmaVec::with_capacity(1000, CacheMode::WriteBack).unwrap();
+    assert!(vec.capacity() >= 1000);
+    for i in 0..1000u64 {
+        vec.push(i).unwrap();
+    }
+    // spans two pages
+    assert_eq!(read_phys(vec.phys_addr().as_u64() + 999 * 8), 999);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn pages_are_reused() {
+    serial_print!("pages_are_reused...");
+    // the whole uncached half of the pool
+    let size = DMA_POOL_SIZE / 2;
+    let vec = DmaVec::<u8>::with_capacity(size, CacheMode::Uncached).unwrap();
+    assert_eq!(DmaBuffer::new(0u8, CacheMode::Uncached).unwrap_err(), DmaError::OutOfMemory);
+    drop(vec);
+    let vec = DmaVec::<u8>::with_capacity(size, CacheMode::Uncached).unwrap();
+    assert_eq!(vec.capacity(), size);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,343 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::cell::{Cell, RefCell};
+use rust_os::{serial_print, serial_println};
+use rust_os::task::{self, yield_now, SendTask, Task, executor::Executor, join::JoinError};
+use rust_os::task::scheduler::{Fifo, Priority, PriorityAging, SchedulingPolicy, WeightedFair};
+use rust_os::task::stats::TaskState;
+use rust_os::task::local::AccessError;
+use rust_os::task_local;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn block_on_returns_output() {
+    serial_print!("block_on_returns_output...");
+    let output = task::block_on(async {
+        yield_now().await;
+        42
+    });
+    assert_eq!(output, 42);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn join_handle_output() {
+    serial_print!("join_handle_output...");
+    let mut executor = Executor::new();
+    let handle = executor.spawn(Task::new(async {
+        yield_now().await;
+        "done"
+    }));
+    assert!(!handle.is_finished());
+    assert_eq!(executor.block_on(handle), Ok("done"));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn abort_task() {
+    serial_print!("abort_task...");
+    let mut executor = Executor::new();
+    // never woken
+    let handle = executor.spawn(Task::new(futures_util::future::pending::<()>()));
+    executor.run_until_idle();
+    assert!(!handle.is_finished());
+    handle.abort();
+    assert_eq!(executor.block_on(handle), Err(JoinError::Aborted));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn more_than_100_tasks() {
+    serial_print!("more_than_100_tasks...");
+    let mut executor = Executor::new();
+    let counter = Rc::new(Cell::new(0));
+    let handles: Vec<_> = (0..200)
+        .map(|_| {
+            let counter = counter.clone();
+            executor.spawn(Task::new(async move {
+                yield_now().await;
+                counter.set(counter.get() + 1);
+            }))
+        })
+        .collect();
+    executor.run_until_idle();
+    assert_eq!(counter.get(), 200);
+    assert!(handles.iter().all(|handle| handle.is_finished()));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn spawn_from_task() {
+    serial_print!("spawn_from_task...");
+    let output = task::block_on(async {
+        let handle = task::spawn(Task::new(async { 1 + 1 }));
+        handle.await.unwrap()
+    });
+    assert_eq!(output, 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn run_returns_on_shutdown() {
+    serial_print!("run_returns_on_shutdown...");
+    let mut executor = Executor::new();
+    let shutdown = executor.shutdown_signal();
+    executor.spawn(Task::new(async move {
+        yield_now().await;
+        shutdown.shutdown();
+    }));
+    executor.run();
+    serial_println!("[ok]");
+}
+
+// spawns one task per priority in `priorities`, each yielding `polls - 1` times and pushing its
+// priority to the returned log on every poll
+fn run_logged(executor: &mut Executor, priorities: &[Priority], polls: usize) -> Rc<RefCell<Vec<Priority>>> {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    for &priority in priorities {
+        let log = log.clone();
+        executor.spawn(Task::new(async move {
+            for _ in 1..polls {
+                log.borrow_mut().push(priority);
+                yield_now().await;
+            }
+            log.borrow_mut().push(priority);
+        }).with_priority(priority));
+    }
+    executor.run_until_idle();
+    log
+}
+
+#[test_case]
+fn high_priority_first() {
+    serial_print!("high_priority_first...");
+    let mut executor = Executor::new();
+    let log = run_logged(&mut executor, &[Priority::Low, Priority::Normal, Priority::High], 1);
+    assert_eq!(*log.borrow(), vec![Priority::High, Priority::Normal, Priority::Low]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn fifo_ignores_priority() {
+    serial_print!("fifo_ignores_priority...");
+    let mut executor = Executor::with_policy(Fifo);
+    let log = run_logged(&mut executor, &[Priority::Low, Priority::Normal, Priority::High], 1);
+    assert_eq!(*log.borrow(), vec![Priority::Low, Priority::Normal, Priority::High]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn aging_prevents_starvation() {
+    serial_print!("aging_prevents_starvation...");
+    let mut executor = Executor::with_policy(PriorityAging { aging: 4 });
+    // the high priority task is ready for 100 polls in a row
+    let log = run_logged(&mut executor, &[Priority::High, Priority::Low], 100);
+    let log = log.borrow();
+    let first_low = log.iter().position(|&priority| priority == Priority::Low).unwrap();
+    assert!(first_low < 20);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn weighted_fair_shares() {
+    serial_print!("weighted_fair_shares...");
+    let mut executor = Executor::with_policy(WeightedFair::new([1, 1, 3]));
+    let log = run_logged(&mut executor, &[Priority::Low, Priority::High], 100);
+    let log = log.borrow();
+    // while both are ready, High gets 3 polls for each poll of Low
+    let high = log[..40].iter().filter(|&&priority| priority == Priority::High).count();
+    assert_eq!(high, 30);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn send_tasks_follow_priorities() {
+    serial_print!("send_tasks_follow_priorities...");
+    let mut executor = Executor::new();
+    let log = Arc::new(spin::Mutex::new(Vec::new()));
+    for &priority in &[Priority::Low, Priority::Normal, Priority::High] {
+        let log = log.clone();
+        executor.spawn_send(SendTask::new(async move {
+            log.lock().push(priority);
+        }).with_priority(priority));
+    }
+    // with the local tasks
+    let local_log = run_logged(&mut executor, &[Priority::Low], 1);
+    assert_eq!(*log.lock(), vec![Priority::High, Priority::Normal, Priority::Low]);
+    assert_eq!(*local_log.borrow(), vec![Priority::Low]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn policy_picking_empty_level() {
+    // always picks Low, even when it has no ready task
+    struct AlwaysLow;
+
+    impl SchedulingPolicy for AlwaysLow {
+        fn pick(&mut self, _oldest_ticket: &[Option<u64>; Priority::COUNT], _ticket: u64) -> Priority {
+            Priority::Low
+        }
+    }
+
+    serial_print!("policy_picking_empty_level...");
+    let mut executor = Executor::with_policy(AlwaysLow);
+    let log = run_logged(&mut executor, &[Priority::Normal, Priority::High], 2);
+    // the executor falls back to the highest ready level
+    assert_eq!(*log.borrow(), vec![Priority::High, Priority::High, Priority::Normal, Priority::Normal]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn task_list_stats() {
+    serial_print!("task_list_stats...");
+    let mut executor = Executor::new();
+    let first = executor.spawn(Task::new(async {
+        yield_now().await;
+        yield_now().await;
+    }).with_name("yielding"));
+    executor.spawn(Task::new(futures_util::future::pending::<()>()).with_priority(Priority::High));
+    // nothing polled yet
+    let list = executor.task_list();
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].name, Some("yielding"));
+    assert_eq!(list[0].state, TaskState::Ready);
+    assert_eq!(list[0].stats.polls, 0);
+
+    executor.run_until_idle();
+    assert!(first.is_finished());
+    let list = executor.task_list();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].name, None);
+    assert_eq!(list[0].priority, Priority::High);
+    assert_eq!(list[0].state, TaskState::Waiting);
+    assert_eq!(list[0].stats.polls, 1);
+    assert_eq!(list[0].stats.wakes, 0);
+    assert!(list[0].stats.max_poll_cycles <= list[0].stats.total_poll_cycles);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn task_list_while_running() {
+    serial_print!("task_list_while_running...");
+    let mut executor = Executor::new();
+    let task_list = executor.task_list_handle();
+    executor.spawn(Task::new(futures_util::future::pending::<()>()).with_name("pending"));
+    let lister = executor.spawn(Task::new(async move {
+        yield_now().await;
+        task_list.get()
+    }).with_name("lister"));
+    let list = executor.block_on(lister).unwrap();
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].name, Some("pending"));
+    assert_eq!(list[0].state, TaskState::Waiting);
+    assert_eq!(list[0].stats.polls, 1);
+    // the lister itself, being polled for the second time
+    assert_eq!(list[1].name, Some("lister"));
+    assert_eq!(list[1].stats.polls, 1);
+    assert_eq!(list[1].stats.wakes, 1);
+    // the handle outlives the tasks it lists
+    assert_eq!(executor.task_list_handle().get().len(), 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn slow_poll_counted() {
+    serial_print!("slow_poll_counted...");
+    let mut executor = Executor::new();
+    // every poll is slow
+    executor.set_slow_poll_threshold(Some(0));
+    executor.spawn(Task::new(async {
+        yield_now().await;
+        futures_util::future::pending::<()>().await
+    }));
+    executor.run_until_idle();
+    let stats = executor.task_list()[0].stats;
+    assert_eq!(stats.polls, 2);
+    assert_eq!(stats.wakes, 1);
+    assert_eq!(stats.slow_polls, 2);
+    serial_println!("[ok]");
+}
+
+task_local! {
+    static REQUEST_ID: u64;
+    static SPAN: &'static str;
+}
+
+// not async, called by the futures
+fn current_request() -> u64 {
+    REQUEST_ID.get()
+}
+
+#[test_case]
+fn task_local_follows_task() {
+    serial_print!("task_local_follows_task...");
+    let mut executor = Executor::new();
+    let handles: Vec<_> = (0..3)
+        .map(|id| {
+            executor.spawn(Task::new(async move {
+                assert_eq!(current_request(), id);
+                // the other tasks are polled in between
+                yield_now().await;
+                assert_eq!(current_request(), id);
+                assert_eq!(SPAN.try_with(|_| ()), Err(AccessError::NotSet));
+                id
+            }).with_local(&REQUEST_ID, id))
+        })
+        .collect();
+    executor.run_until_idle();
+    for (id, handle) in handles.into_iter().enumerate() {
+        assert_eq!(executor.block_on(handle), Ok(id as u64));
+    }
+    assert_eq!(REQUEST_ID.try_with(|_| ()), Err(AccessError::NoTask));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn task_local_set_and_take() {
+    serial_print!("task_local_set_and_take...");
+    let mut executor = Executor::new();
+    let handle = executor.spawn(Task::new(async {
+        assert!(!SPAN.is_set());
+        assert_eq!(SPAN.set("first"), Ok(None));
+        yield_now().await;
+        assert_eq!(SPAN.set("second"), Ok(Some("first")));
+        yield_now().await;
+        SPAN.with(|span| assert_eq!(*span, "second"));
+        SPAN.take()
+    }));
+    assert_eq!(executor.block_on(handle), Ok(Ok(Some("second"))));
+    assert_eq!(SPAN.set("outside"), Err(AccessError::NoTask));
+    serial_println!("[ok]");
+}
@@ -1,6 +1,7 @@
 #![no_std]
 #![no_main]
 #![feature(custom_test_frameworks)]
+#![feature(allocator_api)]
 #![test_runner(rust_os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
@@ -9,7 +10,7 @@ extern crate alloc;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use rust_os::{serial_print, serial_println};
-use rust_os::allocator::HEAP_SIZE;
+use rust_os::allocator::{self, HEAP_SIZE, arena::Arena, object_cache::ObjectCache};
 use alloc::{
     boxed::Box,
     vec::Vec,
@@ -31,6 +32,7 @@ fn main(boot_info: &'static BootInfo) -> ! {
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
 
+    serial_println!("global allocator: {}", allocator::GLOBAL_ALLOCATOR);
     test_main();
     loop {}
 }
@@ -74,6 +76,8 @@ fn many_boxes() {
     serial_println!("[ok]");
 }
 
+// the bump allocator only reuses memory once every allocation is freed
+#[cfg(not(feature = "bump"))]
 #[test_case]
 fn many_boxes_long_lived() {
     // need to reuse freed memory
@@ -86,4 +90,318 @@ fn many_boxes_long_lived() {
 
     assert_eq!(*long_lived, 42);
     serial_println!("[ok]");
-}
\ No newline at end of file
+}
+
+// the bump allocator can't reuse memory while the other blocks are alive
+#[cfg(not(feature = "bump"))]
+#[test_case]
+fn fragmentation() {
+    use alloc::vec;
+
+    // blocks freed in a different order than they were allocated must be merged back
+    // into a free region large enough for half the heap
+    serial_print!("fragmentation...");
+    let mut blocks = Vec::with_capacity(20);
+    for i in 0..20 {
+        blocks.push(vec![i as u8; 2500 + i * 64]);
+    }
+
+    // free every other block first, then the remaining ones
+    let mut i = 0;
+    blocks.retain(|_| {
+        i += 1;
+        i % 2 == 0
+    });
+    drop(blocks);
+
+    let large = vec![42u8; HEAP_SIZE / 2];
+    assert!(large.iter().all(|&x| x == 42));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn stats_track_allocations() {
+    serial_print!("stats_track_allocations...");
+    let before = allocator::stats();
+    let x = Box::new([0u8; 100]);
+    let during = allocator::stats();
+    assert_eq!(during.bytes_allocated, before.bytes_allocated + 100);
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.peak_bytes_allocated >= during.bytes_allocated);
+
+    drop(x);
+    let after = allocator::stats();
+    assert_eq!(after.bytes_allocated, before.bytes_allocated);
+    assert_eq!(after.deallocations, before.deallocations + 1);
+    serial_println!("[ok]");
+}
+
+// heap_debug keeps the freed blocks in its quarantine
+#[cfg(all(feature = "fixed_size_block", not(feature = "heap_debug")))]
+#[test_case]
+fn free_slabs_are_released() {
+    use rust_os::allocator::fixed_size_block::SLAB_SIZE;
+
+    serial_print!("free_slabs_are_released...");
+    let before = allocator::stats();
+    let boxes: Vec<Box<u64>> = (0..2000).map(Box::new).collect();
+    assert!(allocator::stats().free_bytes < before.free_bytes);
+
+    drop(boxes);
+    // every slab taken for the boxes went back to the fallback heap, apart from the spare one
+    let after = allocator::stats();
+    assert!(after.free_bytes + SLAB_SIZE >= before.free_bytes);
+    serial_println!("[ok]");
+}
+
+// heap_debug surrounds the blocks with red zones, they don't come from the same size class
+#[cfg(all(feature = "fixed_size_block", not(feature = "heap_debug")))]
+#[test_case]
+fn spare_slab_is_kept() {
+    serial_print!("spare_slab_is_kept...");
+    // the size class gets its spare slab
+    drop(Box::new(0u64));
+    let before = allocator::stats();
+    for i in 0..100u64 {
+        let x = Box::new(i);
+        // taken from the spare slab, not from the fallback heap
+        assert_eq!(allocator::stats().free_bytes, before.free_bytes);
+        drop(x);
+    }
+    serial_println!("[ok]");
+}
+
+// heap_debug always moves the block on realloc
+#[cfg(not(feature = "heap_debug"))]
+#[test_case]
+fn realloc_same_size_class() {
+    use alloc::alloc::{alloc, dealloc, realloc, Layout};
+
+    serial_print!("realloc_same_size_class...");
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        ptr.write_bytes(0x42, 40);
+        let new_ptr = realloc(ptr, layout, 60);
+        assert_eq!(new_ptr, ptr);
+        assert!((0..40).all(|i| *new_ptr.add(i) == 0x42));
+        dealloc(new_ptr, Layout::from_size_align(60, 8).unwrap());
+    }
+    serial_println!("[ok]");
+}
+
+#[cfg(all(not(feature = "heap_debug"), any(feature = "linked_list", feature = "fixed_size_block")))]
+#[test_case]
+fn realloc_grows_in_place() {
+    use alloc::alloc::{alloc, dealloc, realloc, Layout};
+
+    serial_print!("realloc_grows_in_place...");
+    // too large for the block lists, so it comes from the (fallback) linked list heap
+    let layout = Layout::from_size_align(4000, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        // the end of the free region the block was taken from is still free
+        let new_ptr = realloc(ptr, layout, 6000);
+        assert_eq!(new_ptr, ptr);
+        let new_layout = Layout::from_size_align(6000, 8).unwrap();
+        // shrinking gives back the end of the block
+        assert_eq!(realloc(new_ptr, new_layout, 4000), ptr);
+        dealloc(ptr, layout);
+    }
+    serial_println!("[ok]");
+}
+
+#[cfg(feature = "buddy")]
+#[test_case]
+fn buddy_large_alignment() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    serial_print!("buddy_large_alignment...");
+    for &align in &[256, 4096, 16384] {
+        let layout = Layout::from_size_align(100, align).unwrap();
+        unsafe {
+            let ptr = alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            ptr.write_bytes(0x42, 100);
+            dealloc(ptr, layout);
+        }
+    }
+    serial_println!("[ok]");
+}
+
+// heap_debug adds headers to the blocks and keeps the freed ones in its quarantine
+#[cfg(all(feature = "buddy", not(feature = "heap_debug")))]
+#[test_case]
+fn buddy_split_and_merge() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    serial_print!("buddy_split_and_merge...");
+    let before = allocator::stats();
+    // rounded up to a 64 bytes block, split from a larger one
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        assert_eq!(allocator::stats().free_bytes, before.free_bytes - 64);
+        dealloc(ptr, layout);
+    }
+    // merged back with its buddies
+    let after = allocator::stats();
+    assert_eq!(after.free_bytes, before.free_bytes);
+    assert_eq!(after.largest_free_block, before.largest_free_block);
+    serial_println!("[ok]");
+}
+
+#[cfg(all(feature = "buddy", not(feature = "heap_debug")))]
+#[test_case]
+fn buddy_non_lifo_frees() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    serial_print!("buddy_non_lifo_frees...");
+    let before = allocator::stats();
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let blocks: Vec<*mut u8> = (0..16).map(|_| unsafe { alloc(layout) }).collect();
+    assert!(blocks.iter().all(|ptr| !ptr.is_null()));
+
+    unsafe {
+        // the even blocks first, then the odd ones backwards: no buddy is freed right after
+        // the other half of its parent
+        for ptr in blocks.iter().step_by(2) {
+            dealloc(*ptr, layout);
+        }
+        for ptr in blocks.iter().skip(1).step_by(2).rev() {
+            dealloc(*ptr, layout);
+        }
+    }
+    drop(blocks);
+
+    let after = allocator::stats();
+    assert_eq!(after.free_bytes, before.free_bytes);
+    assert_eq!(after.largest_free_block, before.largest_free_block);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn object_cache_reuses_slots() {
+    serial_print!("object_cache_reuses_slots...");
+    let cache: ObjectCache<[u64; 4]> = ObjectCache::new("test");
+    let objects: Vec<_> = (0..200).map(|i| cache.alloc_with([i; 4]).unwrap()).collect();
+    for (i, object) in objects.iter().enumerate() {
+        assert_eq!(**object, [i as u64; 4]);
+    }
+
+    let stats = cache.stats();
+    assert_eq!(stats.objects_in_use, 200);
+    let slabs = stats.slabs;
+    drop(objects);
+    assert_eq!(cache.stats().objects_in_use, 0);
+
+    // freed slots are reused before creating new slabs
+    let objects: Vec<_> = (0..200).map(|i| cache.alloc_with([i; 4]).unwrap()).collect();
+    assert_eq!(cache.stats().slabs, slabs);
+    drop(objects);
+
+    assert_eq!(cache.shrink(), slabs * 4096);
+    assert_eq!(cache.stats().slabs, 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn object_cache_constructor() {
+    serial_print!("object_cache_constructor...");
+    let cache = ObjectCache::with_constructor("test_constructor", || Vec::<u8>::with_capacity(16), None);
+    let mut object = cache.alloc().unwrap();
+    assert!(object.is_empty() && object.capacity() >= 16);
+    object.push(1);
+    // must be given back in its constructed state
+    object.clear();
+    drop(object);
+
+    assert_eq!(cache.stats().objects_in_use, 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn object_cache_constructor_uses_cache() {
+    use rust_os::allocator::object_cache::ObjectCacheStats;
+
+    // the objects remember the stats of their cache when they were built
+    static CACHE: ObjectCache<ObjectCacheStats> = ObjectCache::with_constructor("test_reentrant", build, None);
+
+    fn build() -> ObjectCacheStats {
+        CACHE.stats()
+    }
+
+    serial_print!("object_cache_constructor_uses_cache...");
+    let object = CACHE.alloc().unwrap();
+    // built before its slab was added to the cache
+    assert_eq!(object.slabs, 0);
+    drop(object);
+    assert_eq!(CACHE.shrink(), 4096);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn arena_vec() {
+    use alloc::alloc::{Allocator, Layout};
+
+    serial_print!("arena_vec...");
+    let before = allocator::stats();
+    {
+        let arena = Arena::new(8 * 1024).unwrap();
+        let mut vec = Vec::new_in(&arena);
+        for i in 0..1000u32 {
+            vec.push(i);
+        }
+        assert_eq!(vec.iter().sum::<u32>(), 999 * 1000 / 2);
+        // the vec grew in place, so it's the only thing in the arena
+        assert_eq!(arena.stats().bytes_allocated, vec.capacity() * 4);
+
+        let boxed = Box::new_in(42u64, &arena);
+        assert_eq!(*boxed, 42);
+        // full
+        let layout = Layout::array::<u8>(arena.capacity()).unwrap();
+        assert!(arena.allocate(layout).is_err());
+    }
+    // the arena pages went back to the heap
+    assert_eq!(allocator::stats().bytes_allocated, before.bytes_allocated);
+    serial_println!("[ok]");
+}
+
+// the bump allocator can't reuse the memory released by the callback
+#[cfg(not(feature = "bump"))]
+#[test_case]
+fn memory_pressure_callback() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use rust_os::allocator::pressure;
+
+    static RESERVE: spin::Mutex<Option<Vec<u8>>> = spin::Mutex::new(None);
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn release_reserve(_layout: alloc::alloc::Layout) -> usize {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        match RESERVE.try_lock().and_then(|mut reserve| reserve.take()) {
+            Some(reserve) => reserve.capacity(),
+            None => 0,
+        }
+    }
+
+    serial_print!("memory_pressure_callback...");
+    let id = pressure::register(release_reserve).unwrap();
+
+    // fails even after running the callback, but doesn't panic
+    assert!(allocator::try_vec_with_capacity::<u8>(HEAP_SIZE * 2).is_err());
+    let calls = CALLS.load(Ordering::Relaxed);
+    assert!(calls > 0);
+
+    // only fits once the reserve is released
+    *RESERVE.lock() = Some(allocator::try_vec_with_capacity(HEAP_SIZE * 6 / 10).unwrap());
+    let vec = allocator::try_vec_with_capacity::<u8>(HEAP_SIZE * 6 / 10).unwrap();
+    assert!(CALLS.load(Ordering::Relaxed) > calls);
+    assert!(RESERVE.lock().is_none());
+    drop(vec);
+
+    pressure::unregister(id);
+    assert_eq!(*allocator::try_box(42).unwrap(), 42);
+    serial_println!("[ok]");
+}
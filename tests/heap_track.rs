@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{serial_print, serial_println};
+use rust_os::allocator;
+use alloc::{boxed::Box, vec::Vec};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn snapshot_diff_shows_growth() {
+    serial_print!("snapshot_diff_shows_growth...");
+    let before = allocator::snapshot();
+    let leaked: Vec<Box<[u8; 64]>> = (0..10).map(|_| Box::new([0; 64])).collect();
+    let after = allocator::snapshot();
+
+    let growth = before.diff(&after);
+    // the 10 boxes come from the same call site
+    assert!(growth.iter().any(|site| site.allocations == 10 && site.bytes == 640));
+
+    drop(leaked);
+    let growth = before.diff(&allocator::snapshot());
+    assert!(growth.iter().all(|site| site.allocations != 10));
+    serial_println!("[ok]");
+}
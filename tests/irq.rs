@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::sync::atomic::{AtomicU64, Ordering};
+use futures_util::StreamExt;
+use rust_os::{serial_print, serial_println};
+use rust_os::task::{Task, executor::Executor};
+use rust_os::task::irq::{IrqEvent, PushError};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn overflows_are_counted() {
+    static EVENT: IrqEvent<u32> = IrqEvent::new();
+
+    serial_print!("overflows_are_counted...");
+    assert_eq!(EVENT.push(0), Err(PushError::NoStream));
+    let mut stream = EVENT.stream(4);
+    for value in 1..=4 {
+        assert_eq!(EVENT.push(value), Ok(()));
+    }
+    assert_eq!(EVENT.push(5), Err(PushError::Full));
+    assert_eq!(stream.overflows(), 1);
+    assert_eq!(stream.len(), 4);
+
+    let values: Vec<u32> = core::iter::from_fn(|| stream.try_next()).collect();
+    assert_eq!(values, [1, 2, 3, 4]);
+    assert!(stream.is_empty());
+    assert_eq!(EVENT.push(6), Ok(()));
+    assert_eq!(stream.try_next(), Some(6));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn push_wakes_the_task() {
+    static EVENT: IrqEvent<u64> = IrqEvent::new();
+
+    serial_print!("push_wakes_the_task...");
+    let mut executor = Executor::new();
+    let sum = Arc::new(AtomicU64::new(0));
+    let handle = {
+        let sum = sum.clone();
+        executor.spawn(Task::new(async move {
+            let mut stream = EVENT.stream(8);
+            while let Some(value) = stream.next().await {
+                sum.fetch_add(value, Ordering::SeqCst);
+                if value == 0 {
+                    break;
+                }
+            }
+        }))
+    };
+    executor.run_until_idle();
+    // pushed as an interrupt handler would, outside of the executor
+    for value in (0..=3).rev() {
+        EVENT.push(value).unwrap();
+        executor.run_until_idle();
+    }
+    assert!(handle.is_finished());
+    assert_eq!(sum.load(Ordering::SeqCst), 6);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,204 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    cell::Cell,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use rust_os::{serial_print, serial_println};
+use rust_os::{smp, thread};
+use rust_os::task::{executor::Executor, sync::mpsc, yield_now, SendTask, Task};
+use rust_os::task_local;
+
+entry_point!(main);
+
+// QEMU is started with `-smp 4` (see Cargo.toml)
+const CPUS: usize = 4;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init(&mut mapper, &mut frame_allocator).expect("thread initialization failed");
+    smp::init(&mut mapper, &mut frame_allocator, phys_memory_offset).expect("SMP initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn busy(iterations: u64) {
+    let mut counter = 0;
+    for i in 0..iterations {
+        unsafe { ptr::write_volatile(&mut counter, i) };
+    }
+}
+
+#[test_case]
+fn one_worker_per_cpu() {
+    serial_print!("one_worker_per_cpu...");
+    assert_eq!(Executor::new().workers(), CPUS);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn idle_cpus_steal_tasks() {
+    serial_print!("idle_cpus_steal_tasks...");
+    let mut executor = Executor::new();
+    let done = Arc::new(AtomicUsize::new(0));
+    // bit n set when the CPU n polled a task
+    let cpus_used = Arc::new(AtomicUsize::new(0));
+
+    // all spawned from this CPU, in the queue of its worker: the others only get them by stealing
+    let handles: Vec<_> = (0..32)
+        .map(|_| {
+            let done = done.clone();
+            let cpus_used = cpus_used.clone();
+            executor.spawn_send(SendTask::new(async move {
+                cpus_used.fetch_or(1 << smp::cpu_index(), Ordering::SeqCst);
+                busy(200_000);
+                done.fetch_add(1, Ordering::SeqCst);
+            }))
+        })
+        .collect();
+    let shutdown = executor.shutdown_signal();
+    executor.spawn_send(SendTask::new(async move {
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        shutdown.shutdown();
+    }));
+    executor.run();
+
+    assert_eq!(done.load(Ordering::SeqCst), 32);
+    assert!(cpus_used.load(Ordering::SeqCst).count_ones() > 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn wakes_across_cpus() {
+    serial_print!("wakes_across_cpus...");
+    let mut executor = Executor::new();
+    let (ping_sender, mut ping_receiver) = mpsc::unbounded_channel();
+    let (pong_sender, mut pong_receiver) = mpsc::unbounded_channel();
+
+    let echo = executor.spawn_send(SendTask::new(async move {
+        while let Some(value) = ping_receiver.recv().await {
+            pong_sender.send(value + 1).unwrap();
+        }
+    }));
+    let shutdown = executor.shutdown_signal();
+    executor.spawn_send(SendTask::new(async move {
+        let mut value = 0;
+        for _ in 0..100 {
+            ping_sender.send(value).unwrap();
+            value = pong_receiver.recv().await.unwrap();
+        }
+        assert_eq!(value, 100);
+        drop(ping_sender);
+        echo.await.unwrap();
+        shutdown.shutdown();
+    }));
+    executor.run();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn executor_runs_again() {
+    serial_print!("executor_runs_again...");
+    let mut executor = Executor::new();
+    for round in 0..3 {
+        let shutdown = executor.shutdown_signal();
+        let handle = executor.spawn_send(SendTask::new(async move { round * 2 }));
+        executor.spawn_send(SendTask::new(async move {
+            assert_eq!(handle.await.unwrap(), round * 2);
+            shutdown.shutdown();
+        }));
+        executor.run();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn local_task_awaits_send_tasks() {
+    serial_print!("local_task_awaits_send_tasks...");
+    let mut executor = Executor::new();
+    let handles: Vec<_> = (0..8u64)
+        .map(|i| executor.spawn_send(SendTask::new(async move {
+            busy(100_000);
+            i
+        })))
+        .collect();
+    // Rc: only polled by this CPU
+    let total = Rc::new(Cell::new(0));
+    let local_total = total.clone();
+    let shutdown = executor.shutdown_signal();
+    executor.spawn(Task::new(async move {
+        for handle in handles {
+            local_total.set(local_total.get() + handle.await.unwrap());
+        }
+        shutdown.shutdown();
+    }));
+    executor.run();
+    assert_eq!(total.get(), 28);
+    serial_println!("[ok]");
+}
+
+task_local! {
+    static REQUEST_ID: u64;
+}
+
+#[test_case]
+fn task_locals_follow_send_tasks() {
+    serial_print!("task_locals_follow_send_tasks...");
+    let mut executor = Executor::new();
+    let handles: Vec<_> = (0..16u64)
+        .map(|id| {
+            executor.spawn_send(SendTask::new(async move {
+                for _ in 0..10 {
+                    assert_eq!(REQUEST_ID.get(), id);
+                    busy(10_000);
+                    // polled again by any CPU
+                    yield_now().await;
+                }
+                // replaced, still seen after the next await
+                REQUEST_ID.set(id * 100).unwrap();
+                yield_now().await;
+                REQUEST_ID.get()
+            }).with_local(&REQUEST_ID, id))
+        })
+        .collect();
+    let shutdown = executor.shutdown_signal();
+    let results = Arc::new(spin::Mutex::new(Vec::new()));
+    let task_results = results.clone();
+    executor.spawn_send(SendTask::new(async move {
+        for handle in handles {
+            task_results.lock().push(handle.await.unwrap());
+        }
+        shutdown.shutdown();
+    }));
+    executor.run();
+    assert_eq!(*results.lock(), (0..16).map(|id| id * 100).collect::<Vec<_>>());
+    serial_println!("[ok]");
+}
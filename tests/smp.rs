@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use rust_os::{serial_print, serial_println};
+use rust_os::{acpi, smp, thread};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// QEMU is started with `-smp 4` (see Cargo.toml)
+const CPUS: usize = 4;
+
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+
+    rust_os::init();
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init(&mut mapper, &mut frame_allocator).expect("thread initialization failed");
+    smp::init(&mut mapper, &mut frame_allocator, phys_memory_offset).expect("SMP initialization failed");
+    unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset };
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_lists_all_cpus() {
+    serial_print!("madt_lists_all_cpus...");
+    let madt = acpi::find_madt(VirtAddr::new(unsafe { PHYS_MEM_OFFSET })).unwrap();
+    assert_eq!(madt.apic_ids.len(), CPUS);
+    assert_ne!(madt.local_apic_address, 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn all_cpus_checked_in() {
+    serial_print!("all_cpus_checked_in...");
+    assert_eq!(smp::online(), CPUS);
+    let cpus = smp::cpus();
+    assert_eq!(cpus.len(), CPUS);
+    // each AP found its own data through GS
+    let indexes: Vec<usize> = cpus.iter().map(|cpu| cpu.index()).collect();
+    assert_eq!(indexes, (0..CPUS).collect::<Vec<_>>());
+    let mut apic_ids: Vec<u32> = cpus.iter().map(|cpu| cpu.apic_id()).collect();
+    apic_ids.sort();
+    apic_ids.dedup();
+    assert_eq!(apic_ids.len(), CPUS);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn bootstrap_cpu_is_first() {
+    serial_print!("bootstrap_cpu_is_first...");
+    let cpu = smp::current();
+    assert_eq!(cpu.index(), 0);
+    assert_eq!(cpu.apic_id(), smp::cpus()[0].apic_id());
+    serial_println!("[ok]");
+}
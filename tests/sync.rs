@@ -0,0 +1,225 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::cell::RefCell;
+use futures_util::stream::StreamExt;
+use rust_os::{serial_print, serial_println};
+use rust_os::task::{self, yield_now, Task};
+use rust_os::task::sync::{broadcast, mpsc, oneshot, Mutex, Notify, RwLock, Semaphore};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn mpsc_bounded() {
+    serial_print!("mpsc_bounded...");
+    let received = task::block_on(async {
+        let (sender, mut receiver) = mpsc::channel(2);
+        task::spawn(Task::new(async move {
+            for i in 0..10 {
+                sender.send(i).await.unwrap();
+            }
+        }));
+        let mut received = Vec::new();
+        while let Some(value) = receiver.recv().await {
+            received.push(value);
+        }
+        received
+    });
+    assert_eq!(received, (0..10).collect::<Vec<_>>());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn mpsc_try_send() {
+    serial_print!("mpsc_try_send...");
+    let (sender, mut receiver) = mpsc::channel(1);
+    assert_eq!(sender.try_send(1), Ok(()));
+    assert_eq!(sender.try_send(2), Err(mpsc::TrySendError::Full(2)));
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(receiver.try_recv(), Err(mpsc::TryRecvError::Empty));
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.try_send(3), Err(mpsc::TrySendError::Closed(3)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn mpsc_unbounded_stream() {
+    serial_print!("mpsc_unbounded_stream...");
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let other = sender.clone();
+    sender.send(1).unwrap();
+    other.send(2).unwrap();
+    drop(sender);
+    drop(other);
+    let received: Vec<i32> = task::block_on(receiver.collect());
+    assert_eq!(received, vec![1, 2]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn oneshot_send() {
+    serial_print!("oneshot_send...");
+    let output = task::block_on(async {
+        let (sender, receiver) = oneshot::channel();
+        task::spawn(Task::new(async move {
+            yield_now().await;
+            sender.send("hello").unwrap();
+        }));
+        receiver.await
+    });
+    assert_eq!(output, Ok("hello"));
+
+    let (sender, receiver) = oneshot::channel::<()>();
+    drop(sender);
+    assert_eq!(task::block_on(receiver), Err(oneshot::RecvError));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn broadcast_lagged() {
+    serial_print!("broadcast_lagged...");
+    let (sender, mut first) = broadcast::channel(2);
+    let mut second = sender.subscribe();
+    assert_eq!(sender.send(1), Ok(2));
+    assert_eq!(first.try_recv(), Ok(1));
+    sender.send(2).unwrap();
+    sender.send(3).unwrap();
+    // `second` missed 1, the buffer only keeps 2 and 3
+    assert_eq!(second.try_recv(), Err(broadcast::TryRecvError::Lagged(1)));
+    assert_eq!(second.try_recv(), Ok(2));
+    drop(sender);
+    task::block_on(async {
+        assert_eq!(first.recv().await, Ok(2));
+        assert_eq!(first.recv().await, Ok(3));
+        assert_eq!(first.recv().await, Err(broadcast::RecvError::Closed));
+    });
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn mutex_held_across_await() {
+    serial_print!("mutex_held_across_await...");
+    let log = task::block_on(async {
+        let mutex = Rc::new(Mutex::new(Vec::new()));
+        let handles: Vec<_> = (0..3)
+            .map(|i| {
+                let mutex = mutex.clone();
+                task::spawn(Task::new(async move {
+                    let mut log = mutex.lock().await;
+                    log.push(i);
+                    // the other tasks run but can't take the lock
+                    yield_now().await;
+                    log.push(i);
+                }))
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        let log = mutex.lock().await.clone();
+        log
+    });
+    assert_eq!(log, vec![0, 0, 1, 1, 2, 2]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn rwlock_writer_waits_for_readers() {
+    serial_print!("rwlock_writer_waits_for_readers...");
+    let lock = RwLock::new(0);
+    let read = lock.try_read().unwrap();
+    assert!(lock.try_read().is_some());
+    assert!(lock.try_write().is_none());
+    drop(read);
+    let mut write = lock.try_write().unwrap();
+    *write += 1;
+    assert!(lock.try_read().is_none());
+    drop(write);
+    assert_eq!(*task::block_on(lock.read()), 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn semaphore_limits_tasks() {
+    serial_print!("semaphore_limits_tasks...");
+    let max_running = task::block_on(async {
+        let semaphore = Rc::new(Semaphore::new(2));
+        let running = Rc::new(RefCell::new((0, 0)));
+        let handles: Vec<_> = (0..5)
+            .map(|_| {
+                let semaphore = semaphore.clone();
+                let running = running.clone();
+                task::spawn(Task::new(async move {
+                    let _permit = semaphore.acquire().await;
+                    {
+                        let mut running = running.borrow_mut();
+                        running.0 += 1;
+                        running.1 = running.1.max(running.0);
+                    }
+                    yield_now().await;
+                    running.borrow_mut().0 -= 1;
+                }))
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(semaphore.available_permits(), 2);
+        let max_running = running.borrow().1;
+        max_running
+    });
+    assert_eq!(max_running, 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn notify_permit() {
+    serial_print!("notify_permit...");
+    let notify = Notify::new();
+    // no waiter: the permit is stored
+    notify.notify_one();
+    task::block_on(notify.notified());
+
+    let notify = Rc::new(Notify::new());
+    task::block_on(async {
+        let waiter = {
+            let notify = notify.clone();
+            task::spawn(Task::new(async move { notify.notified().await }))
+        };
+        yield_now().await;
+        notify.notify_waiters();
+        waiter.await.unwrap();
+    });
+    serial_println!("[ok]");
+}
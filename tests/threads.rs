@@ -0,0 +1,169 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use rust_os::{serial_print, serial_println};
+use rust_os::task::{self, Task};
+use rust_os::thread::{self, SpawnError, MAX_THREADS};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init(&mut mapper, &mut frame_allocator).expect("thread initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_returns_output() {
+    serial_print!("join_returns_output...");
+    let handle = thread::spawn(|| 6 * 7).unwrap();
+    assert_ne!(handle.thread_id(), thread::current());
+    assert_eq!(handle.join(), 42);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn busy_threads_are_preempted() {
+    serial_print!("busy_threads_are_preempted...");
+    let flag = Arc::new(AtomicBool::new(false));
+    // never yields, the other thread only runs if it is preempted
+    let spinning = {
+        let flag = flag.clone();
+        thread::spawn(move || while !flag.load(Ordering::SeqCst) {}).unwrap()
+    };
+    let setter = {
+        let flag = flag.clone();
+        thread::spawn(move || flag.store(true, Ordering::SeqCst)).unwrap()
+    };
+    spinning.join();
+    setter.join();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn sleep_waits_for_ticks() {
+    serial_print!("sleep_waits_for_ticks...");
+    let start = thread::ticks();
+    thread::sleep(50);
+    // 50ms at TIMER_HZ, plus the tick which was running when it was called
+    assert!(thread::ticks() - start >= u64::from(thread::TIMER_HZ) / 20 + 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn block_until_woken() {
+    serial_print!("block_until_woken...");
+    let woken = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let woken = woken.clone();
+        thread::spawn(move || {
+            while !woken.load(Ordering::SeqCst) {
+                thread::block();
+            }
+        })
+        .unwrap()
+    };
+    thread::sleep(20);
+    assert!(!handle.is_finished());
+    woken.store(true, Ordering::SeqCst);
+    thread::wake(handle.thread_id());
+    handle.join();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn stacks_are_reused() {
+    serial_print!("stacks_are_reused...");
+    for i in 0..3 * MAX_THREADS {
+        assert_eq!(thread::spawn(move || i).unwrap().join(), i);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn too_many_threads() {
+    serial_print!("too_many_threads...");
+    let release = Arc::new(AtomicBool::new(false));
+    let started = Arc::new(AtomicUsize::new(0));
+    let mut handles = Vec::new();
+    let error = loop {
+        let release = release.clone();
+        let started = started.clone();
+        match thread::spawn(move || {
+            started.fetch_add(1, Ordering::SeqCst);
+            while !release.load(Ordering::SeqCst) {
+                thread::yield_now();
+            }
+        }) {
+            Ok(handle) => handles.push(handle),
+            Err(error) => break error,
+        }
+    };
+    assert_eq!(error, SpawnError::TooManyThreads);
+    // without the boot and the idle threads
+    assert_eq!(handles.len(), MAX_THREADS - 2);
+    release.store(true, Ordering::SeqCst);
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(started.load(Ordering::SeqCst), MAX_THREADS - 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn executor_in_a_thread() {
+    serial_print!("executor_in_a_thread...");
+    let handle = thread::spawn(|| task::block_on(async { 1 + 1 })).unwrap();
+    assert_eq!(handle.join(), 2);
+    serial_println!("[ok]");
+}
+
+// spawns tasks from `block_on`, letting the other threads run in between
+fn spawn_in_block_on(factor: u64) -> u64 {
+    task::block_on(async move {
+        let mut total = 0;
+        for i in 0..10 {
+            let handle = task::spawn(Task::new(async move { i * factor }));
+            thread::yield_now();
+            total += handle.await.unwrap();
+        }
+        total
+    })
+}
+
+#[test_case]
+fn block_on_in_two_threads() {
+    serial_print!("block_on_in_two_threads...");
+    // each thread spawns on its own executor
+    let first = thread::spawn(|| spawn_in_block_on(1)).unwrap();
+    let second = thread::spawn(|| spawn_in_block_on(100)).unwrap();
+    assert_eq!(first.join(), 45);
+    assert_eq!(second.join(), 4500);
+    serial_println!("[ok]");
+}